@@ -0,0 +1,363 @@
+use common::*;
+use futures::executor::{self, Notify};
+use futures::future;
+use std::sync::Condvar;
+
+/// How long to wait for a notification before deciding that none is coming
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something observed while driving a Future, Stream, or Sink, and when it
+/// was observed, in tenths of a second since the scenario started
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation<T> {
+  pub outcome: T,
+  pub at:      u64,
+}
+
+/// The outcome of a poll, with errors formatted so that standard and
+/// extended implementations with different error types can be compared
+pub type Outcome<T> = Result<T, String>;
+
+/// Everything observed while running a scenario
+pub type Trace<T> = Vec<Observation<Outcome<T>>>;
+
+/// An operation in a sink scenario
+#[derive(Debug, Clone)]
+pub enum Op<T> {
+  /// Call `start_send` once, without retrying if the item is refused
+  Send(T),
+  /// Call `poll_complete` until it is ready
+  Flush,
+  /// Call `close` until it is ready
+  Close,
+  /// Let time pass without touching the sink
+  Sleep(Duration),
+}
+
+/// What a sink operation returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkOutcome<T> {
+  Sent(AsyncSink<T>),
+  Flushed(Async<()>),
+  Closed(Async<()>),
+}
+
+/// Wakes the scenario thread when the task being driven is notified
+struct Wakeup {
+  notified: Mutex<bool>,
+  condvar:  Condvar,
+}
+
+impl Notify for Wakeup {
+  fn notify(&self, _id: usize) {
+    *self.notified.lock().unwrap() = true;
+    self.condvar.notify_all();
+  }
+}
+
+/// Drives a Future, Stream, or Sink from a single task, blocking the
+/// current thread until the task is notified whenever it is not ready
+struct Driver {
+  wakeup: Arc<Wakeup>,
+  start:  Instant,
+}
+
+impl Driver {
+  fn new() -> Driver {
+    Driver {
+      wakeup: Arc::new(Wakeup{notified: Mutex::new(false), condvar: Condvar::new()}),
+      start:  Instant::now(),
+    }
+  }
+
+  /// Run `f` once, inside the driver's task
+  fn poll<T, E: fmt::Debug, F: FnMut() -> Poll<T, E>>(&self, f: F) -> Observation<Outcome<Async<T>>> {
+    let outcome = executor::spawn(future::poll_fn(f)).poll_future_notify(&self.wakeup, 0);
+    Observation{outcome: outcome.map_err(|err| format!("{:?}", err)), at: self.elapsed()}
+  }
+
+  /// Block until the driver's task is notified
+  fn wait(&self) {
+    let mut notified = self.wakeup.notified.lock().unwrap();
+    while !*notified {
+      let (guard, timeout) = self.wakeup.condvar.wait_timeout(notified, NOTIFICATION_TIMEOUT).unwrap();
+      assert!(!timeout.timed_out(), "NotReady was returned, but the task was never notified");
+      notified = guard;
+    }
+    *notified = false;
+  }
+
+  fn elapsed(&self) -> u64 {
+    let elapsed = self.start.elapsed();
+    (elapsed.as_millis() as u64 + 50) / 100
+  }
+}
+
+/// Whether an observation means that the driver should wait and poll again
+fn not_ready<T>(observation: &Observation<Outcome<Async<T>>>) -> bool {
+  matches!(observation.outcome, Ok(Async::NotReady))
+}
+
+/// Add `observation` to `trace`, unless it is a `NotReady` identical to
+/// the last one, since spurious wakeups from timers armed by earlier polls
+/// are not observable behaviour
+fn record<T: PartialEq>(trace: &mut Vec<Observation<Outcome<T>>>, observation: Observation<Outcome<T>>, not_ready: bool) {
+  if !(not_ready && trace.last() == Some(&observation)) {
+    trace.push(observation);
+  }
+}
+
+/// Poll `future` until it resolves, recording every poll
+pub fn future<F>(mut future: F) -> Trace<Async<F::Item>>
+  where F: Future, F::Item: PartialEq, F::Error: fmt::Debug
+{
+  let driver = Driver::new();
+  let mut trace = Vec::new();
+  loop {
+    let observation = driver.poll(|| future.poll());
+    let done = !not_ready(&observation);
+    record(&mut trace, observation, !done);
+    if done {
+      return trace;
+    }
+    driver.wait();
+  }
+}
+
+/// Poll `stream` until it ends, fails, or has yielded `limit` items,
+/// recording every poll
+pub fn stream<S>(mut stream: S, limit: usize) -> Trace<Async<Option<S::Item>>>
+  where S: Stream, S::Item: PartialEq, S::Error: fmt::Debug
+{
+  let driver = Driver::new();
+  let mut trace = Vec::new();
+  let mut items = 0;
+  while items < limit {
+    let observation = driver.poll(|| stream.poll());
+    let (waiting, more) = match observation.outcome {
+      Ok(Async::NotReady) => (true, true),
+      Ok(Async::Ready(Some(_))) => (false, true),
+      Ok(Async::Ready(None)) | Err(_) => (false, false),
+    };
+    record(&mut trace, observation, waiting);
+    if !more {
+      break;
+    }
+    if waiting {
+      driver.wait();
+    } else {
+      items += 1;
+    }
+  }
+  trace
+}
+
+/// Run `script` against `sink`, recording the outcome of every call
+pub fn sink<S>(mut sink: S, script: Vec<Op<S::SinkItem>>) -> Trace<SinkOutcome<S::SinkItem>>
+  where S: Sink, S::SinkItem: PartialEq, S::SinkError: fmt::Debug
+{
+  let driver = Driver::new();
+  let mut trace = Vec::new();
+
+  // record a call which returns `Async`, polling again until it is ready
+  fn repeat<T: PartialEq, E: fmt::Debug, F: FnMut() -> Poll<(), E>>(
+    driver: &Driver,
+    trace: &mut Trace<SinkOutcome<T>>,
+    wrap: fn(Async<()>) -> SinkOutcome<T>,
+    mut f: F,
+  ) {
+    loop {
+      let observation = driver.poll(&mut f);
+      let waiting = not_ready(&observation);
+      record(trace, Observation{outcome: observation.outcome.map(wrap), at: observation.at}, waiting);
+      if !waiting {
+        return;
+      }
+      driver.wait();
+    }
+  }
+
+  for op in script {
+    match op {
+      Op::Send(item) => {
+        let mut item = Some(item);
+        let observation = driver.poll(|| sink.start_send(item.take().unwrap()).map(Async::Ready));
+        trace.push(Observation {
+          outcome: observation.outcome.map(|async_sink| match async_sink {
+            Async::Ready(async_sink) => SinkOutcome::Sent(async_sink),
+            Async::NotReady => unreachable!(),
+          }),
+          at: observation.at,
+        });
+      }
+      Op::Flush => repeat(&driver, &mut trace, SinkOutcome::Flushed, || sink.poll_complete()),
+      Op::Close => repeat(&driver, &mut trace, SinkOutcome::Closed, || sink.close()),
+      Op::Sleep(duration) => thread::sleep(duration),
+    }
+  }
+
+  trace
+}
+
+/// Run the standard and extended versions of a scenario at the same time,
+/// assert that they were observed to behave identically, and return what
+/// was observed
+pub fn assert_conformance<T, S, E>(standard: S, extended: E) -> T
+  where
+    T: PartialEq + fmt::Debug + Send + 'static,
+    S: FnOnce() -> T + Send + 'static,
+    E: FnOnce() -> T + Send + 'static,
+{
+  let standard = thread::spawn(standard);
+  let extended = thread::spawn(extended);
+  let (standard, extended) = (standard.join().unwrap(), extended.join().unwrap());
+  assert_eq!(standard, extended, "standard and extended implementations diverged");
+  standard
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use overflow::Overflow;
+  use recording::Recording;
+  use {extended, standard};
+
+  fn recorded(recording: &Recording<u8>) -> Vec<(u8, u64)> {
+    let start = recording.arrivals().first().cloned();
+    recording.items().into_iter().zip(recording.arrivals())
+      .map(|(item, at)| (item, ((at - start.unwrap()).as_millis() as u64 + 50) / 100))
+      .collect()
+  }
+
+  /// Run `script` against the consumer built by `consumer`, which should pass
+  /// the items it consumes to `recording`, returning the trace along with
+  /// what was recorded
+  fn recorded_sink<K, C>(consumer: C, script: Vec<Op<u8>>) -> (Trace<SinkOutcome<u8>>, Vec<(u8, u64)>)
+    where K: Sink<SinkItem=u8>, K::SinkError: fmt::Debug, C: FnOnce(&Recording<u8>) -> K
+  {
+    let recording = Recording::new();
+    let trace = sink(consumer(&recording), script);
+    (trace, recorded(&recording))
+  }
+
+  #[test]
+  fn sleeper() {
+    assert_conformance(
+      || future(standard::sleeper::Sleeper::new(Duration::from_millis(500))),
+      || future(extended::sleeper::Sleeper::new(Duration::from_millis(500))),
+    );
+  }
+
+  #[test]
+  fn instant() {
+    assert_conformance(
+      || future(standard::instant::Producer::with_seed(7)),
+      || future(extended::instant::Producer::with_seed(7)),
+    );
+    assert_conformance(
+      || future(standard::instant::Consumer::new(1)),
+      || future(extended::instant::Consumer::new(1)),
+    );
+  }
+
+  #[test]
+  fn delayed() {
+    assert_conformance(
+      || future(standard::delayed::Producer::with_seed(7)),
+      || future(extended::delayed::Producer::with_seed(7)),
+    );
+    assert_conformance(
+      || future(standard::delayed::Consumer::new(1)),
+      || future(extended::delayed::Consumer::new(1)),
+    );
+  }
+
+  #[test]
+  fn instant_series() {
+    assert_conformance(
+      || stream(standard::instant_series::Producer::with_seed(7).end_after(5), 10),
+      || stream(extended::instant_series::Producer::with_seed(7).end_after(5), 10),
+    );
+
+    let script = || vec![Op::Send(1), Op::Send(2), Op::Flush, Op::Send(3), Op::Close];
+    assert_conformance(
+      move || recorded_sink(|recording| standard::instant_series::Consumer::with_callback(recording.callback()), script()),
+      move || recorded_sink(|recording| extended::instant_series::Consumer::with_callback(recording.callback()), script()),
+    );
+  }
+
+  #[test]
+  fn delayed_series() {
+    assert_conformance(
+      || stream(standard::delayed_series::Producer::from_items(0..3), 10),
+      || stream(extended::delayed_series::Producer::from_items(0..3), 10),
+    );
+
+    let script = || vec![Op::Send(1), Op::Send(2), Op::Flush, Op::Send(3), Op::Close];
+    assert_conformance(
+      move || recorded_sink(|recording| standard::delayed_series::Consumer::with_callback(recording.callback()), script()),
+      move || recorded_sink(|recording| extended::delayed_series::Consumer::with_callback(recording.callback()), script()),
+    );
+  }
+
+  #[test]
+  fn buffered() {
+    let script = || {
+      let mut script = (0..5).map(Op::Send).collect::<Vec<Op<u8>>>();
+      script.push(Op::Sleep(Duration::from_millis(1500)));
+      script.push(Op::Send(5));
+      script.push(Op::Close);
+      script
+    };
+    assert_conformance(
+      move || recorded_sink(|recording| standard::buffered::Consumer::with_callback(3, recording.callback()), script()),
+      move || recorded_sink(|recording| extended::buffered::Consumer::with_callback(3, recording.callback()), script()),
+    );
+  }
+
+  #[test]
+  fn overflow_policies() {
+    // the first item goes straight to the inner consumer, and the rest land
+    // in the buffer, which is drained when the consumer is closed
+    let cases: &[(Overflow, &[u8], u64, &[u8])] = &[
+      (Overflow::Block,      &[4, 5], 0, &[0, 1, 2, 3]),
+      (Overflow::DropOldest, &[],     2, &[0, 3, 4, 5]),
+      (Overflow::DropNewest, &[],     2, &[0, 1, 2, 3]),
+      // at most one item per key is buffered
+      (Overflow::ConflateByKey(|item| u64::from(*item % 2)), &[], 3, &[0, 5, 4]),
+    ];
+
+    let script = || {
+      let mut script = (0..6).map(Op::Send).collect::<Vec<Op<u8>>>();
+      script.push(Op::Close);
+      script
+    };
+
+    for &(overflow, refused, dropped, consumed) in cases {
+      let (trace, recorded, actually_dropped) = assert_conformance(
+        move || {
+          let recording = Recording::new();
+          let mut consumer = standard::buffered::Consumer::with_callback(3, recording.callback()).with_overflow(overflow);
+          let trace = sink(&mut consumer, script());
+          (trace, recorded(&recording), consumer.dropped())
+        },
+        move || {
+          let recording = Recording::new();
+          let mut consumer = extended::buffered::Consumer::with_callback(3, recording.callback()).with_overflow(overflow);
+          let trace = sink(&mut consumer, script());
+          (trace, recorded(&recording), consumer.dropped())
+        },
+      );
+
+      let actually_refused = trace.into_iter()
+        .filter_map(|observation| match observation.outcome {
+          Ok(SinkOutcome::Sent(AsyncSink::NotReady(item))) => Some(item),
+          _ => None,
+        })
+        .collect::<Vec<u8>>();
+      assert_eq!(actually_refused, refused, "{:?}", overflow);
+      assert_eq!(actually_dropped, dropped, "{:?}", overflow);
+      assert_eq!(recorded.into_iter().map(|(item, _)| item).collect::<Vec<u8>>(), consumed, "{:?}", overflow);
+    }
+  }
+}
@@ -0,0 +1,489 @@
+use common::*;
+use extended::common::*;
+use futures::executor::{self, Notify};
+use futures::future;
+
+/// A broken Future, Stream, or Sink contract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+  /// A future was polled after it resolved or failed
+  PolledAfterReady,
+  /// A stream was polled after it ended
+  PolledAfterEnd,
+  /// `start_send` was called again after it returned `NotReady`, without
+  /// an intervening `poll_complete`
+  SendAfterNotReady,
+  /// `NotReady` was returned, but nothing was holding on to the task to
+  /// notify it, and it had not already been notified
+  NotReadyWithoutNotification,
+  /// The task was notified after the future resolved, the stream ended, or
+  /// the sink was closed
+  NotifiedAfterCompletion,
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    let description = match *self {
+      Violation::PolledAfterReady => "future polled after it completed",
+      Violation::PolledAfterEnd => "stream polled after it ended",
+      Violation::SendAfterNotReady => "start_send called after NotReady without poll_complete",
+      Violation::NotReadyWithoutNotification => "NotReady returned with no notification scheduled",
+      Violation::NotifiedAfterCompletion => "task notified after completion",
+    };
+    write!(f, "{}", description)
+  }
+}
+
+struct State {
+  /// the task polling the checked object, which notifications are passed on to
+  outer:      Option<Task>,
+  /// task handles which could still notify the probe
+  handles:    usize,
+  /// the probe was notified during the current poll
+  notified:   bool,
+  completed:  bool,
+  violations: Vec<Violation>,
+}
+
+/// The task that the checked object is polled in, which keeps track of who
+/// could notify it, and passes notifications on to the real task
+struct Probe {
+  state: Mutex<State>,
+}
+
+impl Probe {
+  fn scheduled(&self) -> bool {
+    let state = self.state.lock().unwrap();
+    state.notified || state.handles > 0
+  }
+
+  fn record(&self, violation: Violation) {
+    self.state.lock().unwrap().violations.push(violation);
+  }
+}
+
+impl Notify for Probe {
+  fn notify(&self, _id: usize) {
+    let outer = {
+      let mut state = self.state.lock().unwrap();
+      if state.completed {
+        state.violations.push(Violation::NotifiedAfterCompletion);
+        return;
+      }
+      state.notified = true;
+      state.outer.clone()
+    };
+    if let Some(outer) = outer {
+      outer.notify();
+    }
+  }
+
+  fn clone_id(&self, id: usize) -> usize {
+    self.state.lock().unwrap().handles += 1;
+    id
+  }
+
+  fn drop_id(&self, _id: usize) {
+    self.state.lock().unwrap().handles -= 1;
+  }
+}
+
+/// The violations found by a `Checked` wrapper, including notifications
+/// which arrive after it has been consumed
+#[derive(Clone)]
+pub struct Report {
+  probe: Arc<Probe>,
+}
+
+impl Report {
+  pub fn violations(&self) -> Vec<Violation> {
+    self.probe.state.lock().unwrap().violations.clone()
+  }
+
+  /// Panic if any violations have been found
+  pub fn assert_clean(&self) {
+    let violations = self.violations();
+    assert!(violations.is_empty(), "contract violations: {:?}", violations);
+  }
+}
+
+/// A wrapper around a standard or extended Future, Stream, or Sink, which
+/// panics as soon as it sees the wrapped object break its contract
+///
+/// The wrapped object is polled in a task of its own, nested inside the
+/// task polling the wrapper, so that the wrapper can see whether a task
+/// handle is still held whenever `NotReady` is returned. Notifications that
+/// arrive after completion happen on other threads, so they are recorded
+/// in the wrapper's `Report` instead.
+///
+/// Note that `forward` and `send_all` retry a refused item without calling
+/// `poll_complete` first, so a sink which refuses items can't be checked
+/// while being driven by them.
+pub struct Checked<T> {
+  inner:     T,
+  probe:     Arc<Probe>,
+  completed: bool,
+  refused:   bool,
+}
+
+impl<T> Checked<T> {
+  pub fn new(inner: T) -> Checked<T> {
+    let state = State {
+      outer:      None,
+      handles:    0,
+      notified:   false,
+      completed:  false,
+      violations: Vec::new(),
+    };
+    Checked{inner, probe: Arc::new(Probe{state: Mutex::new(state)}), completed: false, refused: false}
+  }
+
+  pub fn report(&self) -> Report {
+    Report{probe: self.probe.clone()}
+  }
+
+  pub fn into_inner(self) -> T {
+    self.inner
+  }
+
+  fn violate(&self, violation: Violation) -> ! {
+    self.probe.record(violation.clone());
+    panic!("contract violation: {}", violation);
+  }
+
+  /// Run `f` on the wrapped object inside the probe's task
+  fn probe<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+    {
+      let mut state = self.probe.state.lock().unwrap();
+      state.outer = Some(task::current());
+      state.notified = false;
+    }
+    let inner = &mut self.inner;
+    let mut result = None;
+    let _ = executor::spawn(future::lazy(|| {
+      result = Some(f(inner));
+      Ok::<(), ()>(())
+    })).poll_future_notify(&self.probe, 0);
+    result.expect("probe task did not run")
+  }
+
+  fn not_ready(&self) {
+    if !self.probe.scheduled() {
+      self.violate(Violation::NotReadyWithoutNotification);
+    }
+  }
+
+  fn complete(&mut self) {
+    self.completed = true;
+    self.probe.state.lock().unwrap().completed = true;
+  }
+
+  fn before_poll(&self, violation: Violation) {
+    if self.completed {
+      self.violate(violation);
+    }
+  }
+
+  fn before_start_send(&self) {
+    if self.refused {
+      self.violate(Violation::SendAfterNotReady);
+    }
+  }
+}
+
+impl<T: Future> Future for Checked<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    self.before_poll(Violation::PolledAfterReady);
+    let result = self.probe(|inner| inner.poll());
+    match result {
+      Ok(Async::NotReady) => self.not_ready(),
+      Ok(Async::Ready(_)) | Err(_) => self.complete(),
+    }
+    result
+  }
+}
+
+impl<T: ExtendedFuture> ExtendedFuture for Checked<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    self.before_poll(Violation::PolledAfterReady);
+    let result = self.probe(|inner| inner.extended_poll(task_handle));
+    match result {
+      Ok(ExtendedAsync::NotReady(_)) => self.not_ready(),
+      Ok(ExtendedAsync::Ready(_)) | Err(_) => self.complete(),
+    }
+    result
+  }
+}
+
+impl<T: Stream> Stream for Checked<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    self.before_poll(Violation::PolledAfterEnd);
+    let result = self.probe(|inner| inner.poll());
+    match result {
+      Ok(Async::NotReady) => self.not_ready(),
+      Ok(Async::Ready(None)) => self.complete(),
+      Ok(Async::Ready(Some(_))) | Err(_) => {}
+    }
+    result
+  }
+}
+
+impl<T: ExtendedStream> ExtendedStream for Checked<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    self.before_poll(Violation::PolledAfterEnd);
+    let result = self.probe(|inner| inner.extended_poll(task_handle));
+    match result {
+      Ok(ExtendedAsync::NotReady(_)) => self.not_ready(),
+      Ok(ExtendedAsync::Ready(None)) => self.complete(),
+      Ok(ExtendedAsync::Ready(Some(_))) | Err(_) => {}
+    }
+    result
+  }
+}
+
+impl<T: Sink> Sink for Checked<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = T::SinkError;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    self.before_start_send();
+    let result = self.probe(|inner| inner.start_send(item));
+    if let Ok(AsyncSink::NotReady(_)) = result {
+      self.refused = true;
+      self.not_ready();
+    }
+    result
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    self.refused = false;
+    let result = self.probe(|inner| inner.poll_complete());
+    if let Ok(Async::NotReady) = result {
+      self.not_ready();
+    }
+    result
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    self.refused = false;
+    let result = self.probe(|inner| inner.close());
+    match result {
+      Ok(Async::NotReady) => self.not_ready(),
+      Ok(Async::Ready(())) => self.complete(),
+      Err(_) => {}
+    }
+    result
+  }
+}
+
+impl<T: ExtendedSink> ExtendedSink for Checked<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = T::SinkError;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    self.before_start_send();
+    let result = self.probe(|inner| inner.extended_start_send(task_handle, item));
+    if let Ok(ExtendedAsyncSink::NotReady(..)) = result {
+      self.refused = true;
+      self.not_ready();
+    }
+    result
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.refused = false;
+    let result = self.probe(|inner| inner.extended_poll_complete(task_handle));
+    if let Ok(ExtendedAsync::NotReady(_)) = result {
+      self.not_ready();
+    }
+    result
+  }
+
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.refused = false;
+    let result = self.probe(|inner| inner.extended_close(task_handle));
+    match result {
+      Ok(ExtendedAsync::NotReady(_)) => self.not_ready(),
+      Ok(ExtendedAsync::Ready(())) => self.complete(),
+      Err(_) => {}
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use conformance::{self, Op, SinkOutcome};
+  use extended::recorder::Recorder;
+  use recording::Schedule;
+  use {extended, standard};
+
+  /// A leaf future which returns `NotReady` without arranging to be notified
+  struct Forgetful;
+
+  impl Future for Forgetful {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+      Ok(Async::NotReady)
+    }
+  }
+
+  /// An extended leaf future which agrees to notify, then drops the task
+  struct Faithless;
+
+  impl ExtendedFuture for Faithless {
+    type Item = ();
+    type Error = ();
+
+    fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<(), ()> {
+      let (_task, agreement_to_notify) = task_handle.i_will_notify();
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+
+  impl Future for Faithless {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+      future_adapter(self)
+    }
+  }
+
+  /// A future which resolves when first notified, and is notified again
+  /// after that
+  struct Chatty {
+    started: bool,
+  }
+
+  impl Future for Chatty {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+      if self.started {
+        return Ok(Async::Ready(()));
+      }
+      self.started = true;
+      let task = task::current();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        task.notify();
+        thread::sleep(Duration::from_millis(50));
+        task.notify();
+      });
+      Ok(Async::NotReady)
+    }
+  }
+
+  #[test]
+  fn well_behaved() {
+    let future = Checked::new(standard::sleeper::Sleeper::new(Duration::from_millis(100)));
+    let report = future.report();
+    future.wait().unwrap();
+    report.assert_clean();
+
+    // through the extended API
+    let mut future = Checked::new(extended::delayed::Producer::with_seed(1));
+    let report = future.report();
+    future::poll_fn(|| future_adapter(&mut future)).wait().unwrap();
+    report.assert_clean();
+
+    let stream = Checked::new(extended::instant_series::Producer::from_items(0..3));
+    let report = stream.report();
+    assert_eq!(stream.collect().wait().unwrap(), vec![0, 1, 2]);
+    report.assert_clean();
+
+    let sink = Checked::new(standard::delayed_series::Consumer::new());
+    let report = sink.report();
+    let script = vec![Op::Send(1), Op::Send(2), Op::Flush, Op::Send(3), Op::Close];
+    let trace = conformance::sink(sink, script);
+    assert_eq!(trace[1].outcome, Ok(SinkOutcome::Sent(AsyncSink::NotReady(2))));
+    report.assert_clean();
+  }
+
+  #[test]
+  fn refusing_sink_is_well_behaved() {
+    // the recorder notifies as soon as it refuses an item
+    let sink = Checked::new(Recorder::new().with_schedule(Schedule::new(vec![true, false])));
+    let report = sink.report();
+    let script = vec![Op::Send(1), Op::Flush, Op::Send(1), Op::Close];
+    let trace = conformance::sink(sink, script);
+    assert_eq!(trace[0].outcome, Ok(SinkOutcome::Sent(AsyncSink::NotReady(1))));
+    assert_eq!(trace[2].outcome, Ok(SinkOutcome::Sent(AsyncSink::Ready)));
+    report.assert_clean();
+  }
+
+  #[test]
+  #[should_panic(expected = "future polled after it completed")]
+  fn poll_after_ready() {
+    let mut future = Checked::new(standard::instant::Producer::with_seed(1));
+    future::lazy(|| {
+      let _ = future.poll();
+      future.poll()
+    }).wait().unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "stream polled after it ended")]
+  fn poll_after_end() {
+    let mut stream = Checked::new(extended::instant_series::Producer::from_items(0..1));
+    let _ = stream.by_ref().collect().wait();
+    future::lazy(|| stream.poll()).wait().unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "start_send called after NotReady without poll_complete")]
+  fn send_after_not_ready() {
+    let mut sink = Checked::new(Recorder::new().with_schedule(Schedule::new(vec![true])));
+    future::lazy(|| {
+      let _ = sink.start_send(1);
+      sink.start_send(1)
+    }).wait().unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "NotReady returned with no notification scheduled")]
+  fn standard_not_ready_without_notification() {
+    let _ = Checked::new(Forgetful).wait();
+  }
+
+  #[test]
+  #[should_panic(expected = "NotReady returned with no notification scheduled")]
+  fn extended_not_ready_without_notification() {
+    let mut future = Checked::new(Faithless);
+    let _ = future::poll_fn(|| future_adapter(&mut future)).wait();
+  }
+
+  #[test]
+  fn notified_after_completion() {
+    let future = Checked::new(Chatty{started: false});
+    let report = future.report();
+    future.wait().unwrap();
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(report.violations(), vec![Violation::NotifiedAfterCompletion]);
+  }
+}
@@ -1,99 +1,392 @@
 use common::*;
 use extended::common::*;
 
-#[derive(Debug)]
-pub struct Incoming(u8);
+use extended::sleeper::Sleeper;
+use extended::trace::{Event, Tracer};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+/// What an `Adapter` should do with an item from the underlying stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action<I, R> {
+  /// Pass an item upward to the consumer of the adapter
+  Emit(I),
+  /// Send a reply to the underlying sink
+  Reply(R),
+  /// Pass an item upward and send a reply to the underlying sink
+  Both(I, R),
+  /// Do nothing
+  Skip,
+}
+
+/// The protocol logic of an `Adapter`
+pub trait Processor {
+  /// Items produced by the underlying stream
+  type Input;
+  /// Items consumed by the underlying sink
+  type Output;
+  /// Items that the adapter yields as a stream
+  type Item;
+  /// Items that the adapter accepts as a sink
+  type SinkItem;
+  type Error;
+
+  /// Decide what to do with an item from the underlying stream
+  fn incoming(&mut self, input: Self::Input) -> Result<Action<Self::Item, Self::Output>, Self::Error>;
+
+  /// Convert an item sent to the adapter into an item for the underlying sink
+  fn outgoing(&mut self, item: Self::SinkItem) -> Result<Self::Output, Self::Error>;
+}
+
+/// A queue of control messages for the underlying sink, given to a `Handler`
+pub struct Control<'a, O: 'a> {
+  buffer:   &'a mut VecDeque<O>,
+  capacity: Option<usize>,
+  tracer:   &'a Tracer,
+}
+
+impl<'a, O> Control<'a, O> {
+  /// Queue `message` to be sent to the underlying sink, or return it if the
+  /// adapter's buffer is at capacity
+  pub fn send(&mut self, message: O) -> Result<(), O> {
+    if self.is_full() {
+      return Err(message);
+    }
+    self.buffer.push_back(message);
+    self.tracer.emit("adapter", Event::Buffered{len: self.buffer.len()});
+    Ok(())
+  }
+
+  /// Whether the adapter's buffer is at capacity, so that `send` will refuse
+  /// messages
+  pub fn is_full(&self) -> bool {
+    self.capacity.is_some_and(|capacity| self.buffer.len() >= capacity)
+  }
+}
+
+/// Protocol-level hooks for an `Adapter`, which can answer heartbeats,
+/// acknowledge subscriptions, and send periodic messages without involving
+/// the processor or the application
+///
+/// If the adapter has a capacity, `Control::send` refuses messages while its
+/// buffer is full, and the handler decides whether to drop them or try again
+/// later.
+pub trait Handler<I, O> {
+  /// Inspect an item from the underlying stream, queueing any replies to
+  /// `control`, and returning `true` if the item has been fully handled and
+  /// should not be passed on to the processor
+  fn inspect(&mut self, input: &I, control: &mut Control<O>) -> bool;
+
+  /// How often `tick` should be called, if at all
+  fn interval(&self) -> Option<Duration> {
+    None
+  }
+
+  /// Called every `interval`, queueing any periodic messages to `control`
+  fn tick(&mut self, _control: &mut Control<O>) {}
+}
+
+/// The default handler, which passes everything through to the processor
+impl<I, O> Handler<I, O> for () {
+  fn inspect(&mut self, _input: &I, _control: &mut Control<O>) -> bool {
+    false
+  }
+}
 
+/// Errors produced by an `Adapter`, with `SE`, `KE`, and `PE` being the
+/// errors of the underlying stream, the underlying sink, and the processor
 #[derive(Debug)]
-pub struct Outgoing(u8);
+pub enum Error<SE, KE, PE> {
+  /// The underlying stream failed
+  Stream(SE),
+  /// The underlying sink failed
+  Sink(KE),
+  /// The processor failed
+  Processor(PE),
+}
+
+impl<SE: fmt::Display, KE: fmt::Display, PE: fmt::Display> fmt::Display for Error<SE, KE, PE> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      Error::Stream(ref error) => write!(f, "underlying stream failed: {}", error),
+      Error::Sink(ref error) => write!(f, "underlying sink failed: {}", error),
+      Error::Processor(ref error) => write!(f, "processor failed: {}", error),
+    }
+  }
+}
 
-/// A `Sink + Stream` that does some trivial processing on an
-/// underlying `Sink + Stream`
-pub struct Adapter {
-  buffer:      VecDeque<u8>,
+/// The error type of an `Adapter<S, K, P>`
+pub type AdapterError<S, K, P> = Error<
+  <S as ExtendedStream>::Error,
+  <K as ExtendedSink>::SinkError,
+  <P as Processor>::Error,
+>;
+
+/// A `Sink + Stream` that uses a `Processor` to do some processing on an
+/// underlying `Sink + Stream`, and a `Handler` to take care of control
+/// messages
+pub struct Adapter<S, K, P: Processor, H = ()> {
+  buffer:      VecDeque<P::Output>,
+  /// the most items `buffer` may hold before we stop reading from `stream`
+  capacity:    Option<usize>,
+  /// an item from `stream` which is waiting for room in `buffer` before
+  /// being given to the processor
+  deferred:    Option<P::Input>,
   outstanding: u64,
-  stream:      extended::delayed_series::Producer,
-  sink:        extended::delayed_series::Consumer,
+  /// items have been sent to `sink` since it last completed
+  unflushed:   bool,
+  /// `None` once the adapter has been closed
+  stream:      Option<S>,
+  sink:        K,
+  processor:   P,
+  handler:     H,
+  timer:       Option<Sleeper>,
+  tracer:      Tracer,
+}
+
+impl<S, K, P> Adapter<S, K, P>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+{
+  pub fn new(stream: S, sink: K, processor: P) -> Adapter<S, K, P> {
+    Adapter::with_handler(stream, sink, processor, ())
+  }
 }
 
-impl Adapter {
-  pub fn new() -> Adapter {
+impl<S, K, P, H> Adapter<S, K, P, H>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        H: Handler<P::Input, P::Output>,
+{
+  pub fn with_handler(stream: S, sink: K, processor: P, handler: H) -> Adapter<S, K, P, H> {
+    let timer = handler.interval().map(|interval| {
+      assert!(interval > Duration::new(0, 0), "handler interval must be greater than zero");
+      Sleeper::new(interval)
+    });
+
     Adapter {
       outstanding: 0,
+      unflushed: false,
       buffer: VecDeque::new(),
-      stream: extended::delayed_series::Producer::new(),
-      sink:   extended::delayed_series::Consumer::new(),
+      capacity: None,
+      deferred: None,
+      stream: Some(stream),
+      sink,
+      processor,
+      handler,
+      timer,
+      tracer: Tracer::none(),
     }
   }
 
-  fn try_empty_buffer(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, Void> {
+  /// Report items passing through the adapter, and the handler's timer, to
+  /// `tracer`
+  pub fn with_tracer(mut self, tracer: Tracer) -> Adapter<S, K, P, H> {
+    self.timer = self.timer.take().map(|timer| timer.with_tracer(tracer.clone()));
+    self.tracer = tracer;
+    self
+  }
+
+  /// Limit the number of items waiting to be sent to the underlying sink to
+  /// `capacity`. While the limit is reached, the underlying stream is not
+  /// polled, and items sent to the adapter and control messages sent by the
+  /// handler are refused.
+  pub fn with_capacity(mut self, capacity: usize) -> Adapter<S, K, P, H> {
+    assert!(capacity > 0, "adapter capacity must be greater than zero");
+    self.capacity = Some(capacity);
+    self
+  }
+
+  /// The number of items waiting to be sent to the underlying sink
+  pub fn queued(&self) -> usize {
+    self.buffer.len()
+  }
+
+  /// Queue control messages to be sent to the underlying sink
+  pub fn control(&mut self) -> Control<'_, P::Output> {
+    Control{buffer: &mut self.buffer, capacity: self.capacity, tracer: &self.tracer}
+  }
+
+  /// Replace the underlying stream and sink, for example after the previous
+  /// connection has dropped. Anything queued for the old sink is discarded.
+  pub fn reconnect(&mut self, stream: S, sink: K) {
+    if !self.buffer.is_empty() {
+      self.tracer.emit("adapter", Event::Discarded{count: self.buffer.len()});
+    }
+    self.buffer.clear();
+    self.deferred = None;
+    self.outstanding = 0;
+    self.unflushed = false;
+    self.stream = Some(stream);
+    self.sink = sink;
+  }
+
+  fn is_full(&self) -> bool {
+    self.capacity.is_some_and(|capacity| self.buffer.len() >= capacity)
+  }
+
+  /// Queue an item for the underlying sink, once we know there is room
+  fn queue(&mut self, output: P::Output) {
+    debug_assert!(!self.is_full());
+    self.buffer.push_back(output);
+    self.tracer.emit("adapter", Event::Buffered{len: self.buffer.len()});
+  }
+
+  /// Give the handler a tick for each time the timer has fired
+  fn poll_timer(&mut self, task_handle: &mut TaskHandle) {
+    while let Some(Ok(ExtendedAsync::Ready(()))) = self.timer.as_mut().map(|timer| timer.extended_poll(task_handle)) {
+      self.handler.tick(&mut Control{buffer: &mut self.buffer, capacity: self.capacity, tracer: &self.tracer});
+      let tracer = &self.tracer;
+      self.timer = self.handler.interval().map(|interval| Sleeper::new(interval).with_tracer(tracer.clone()));
+    }
+  }
+
+  fn try_empty_buffer(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, AdapterError<S, K, P>>
+  {
     while let Some(item) = self.buffer.pop_front() {
-      eprintln!("sending to underlying sink: {}", item);
       if let ExtendedAsyncSink::NotReady(item, agreement_to_notify)
-        = self.sink.extended_start_send(task_handle, item)?
+        = self.sink.extended_start_send(task_handle, item).map_err(Error::Sink)?
       {
         self.buffer.push_front(item);
 
         // ensure that we attempt to complete any pushes we've started
-        self.sink.extended_poll_complete(task_handle)?;
+        self.sink.extended_poll_complete(task_handle).map_err(Error::Sink)?;
 
         return Ok(ExtendedAsync::NotReady(agreement_to_notify));
       }
-      self.outstanding -= 1;
+      self.tracer.emit("adapter", Event::Sent);
+      self.outstanding = self.outstanding.saturating_sub(1);
+      self.unflushed = true;
+    }
+
+    if self.unflushed {
+      // the underlying sink may hold on to what we've sent until it's polled
+      // again, and if we're being polled as a stream nobody else will do it
+      if let ExtendedAsync::Ready(()) = self.sink.extended_poll_complete(task_handle).map_err(Error::Sink)? {
+        self.unflushed = false;
+        self.tracer.emit("adapter", Event::Flushed);
+      }
     }
 
     Ok(ExtendedAsync::Ready(()))
   }
 }
 
-impl Drop for Adapter {
+impl<S, K, P: Processor, H> Drop for Adapter<S, K, P, H> {
   fn drop(&mut self) {
-    eprintln!("adapter dropped with {} outstanding", self.outstanding);
+    self.tracer.emit("adapter", Event::Dropped{outstanding: self.outstanding});
   }
 }
 
-impl ExtendedStream for Adapter {
-  type Item = Incoming;
-  type Error = Void;
+impl<S, K, P, H> ExtendedStream for Adapter<S, K, P, H>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        H: Handler<P::Input, P::Output>,
+{
+  type Item = P::Item;
+  type Error = AdapterError<S, K, P>;
   fn extended_poll(&mut self, task_handle: &mut TaskHandle)
     -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
   {
+    self.poll_timer(task_handle);
+
     loop {
-      self.try_empty_buffer(task_handle)?;
+      if let ExtendedAsync::NotReady(agreement_to_notify) = self.try_empty_buffer(task_handle)? {
+        if self.is_full() {
+          return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+        }
+      }
 
-      match extended_try_ready!(self.stream.extended_poll(task_handle)) {
-        Some(byte) => {
-          eprintln!("from underlying stream: {}", byte);
-          self.outstanding += 1;
-          if byte % 2 == 0 {
-            self.buffer.push_back(byte);
-          } else {
-            return Ok(ExtendedAsync::Ready(Some(Incoming(byte))))
+      let input = match self.deferred.take() {
+        Some(input) => input,
+        None => {
+          let next = match self.stream {
+            Some(ref mut stream) => extended_try_ready!(stream.extended_poll(task_handle).map_err(Error::Stream)),
+            None => None,
+          };
+
+          let input = match next {
+            Some(input) => input,
+            None => return Ok(ExtendedAsync::Ready(None)),
+          };
+
+          self.tracer.emit("adapter", Event::Received);
+          let handled = self.handler.inspect(
+            &input,
+            &mut Control{buffer: &mut self.buffer, capacity: self.capacity, tracer: &self.tracer},
+          );
+          if handled {
+            continue;
           }
+
+          // the handler may have filled the buffer, so wait until there is
+          // room for a reply before giving the item to the processor
+          if self.is_full() {
+            self.deferred = Some(input);
+            continue;
+          }
+
+          input
         }
-        None => return Ok(ExtendedAsync::Ready(None)),
+      };
+
+      match self.processor.incoming(input).map_err(Error::Processor)? {
+        Action::Emit(item) => {
+          self.outstanding += 1;
+          return Ok(ExtendedAsync::Ready(Some(item)));
+        }
+        Action::Reply(reply) => {
+          self.outstanding += 1;
+          self.queue(reply);
+        }
+        Action::Both(item, reply) => {
+          self.outstanding += 1;
+          self.queue(reply);
+          return Ok(ExtendedAsync::Ready(Some(item)));
+        }
+        Action::Skip => {}
       }
     }
   }
 }
 
-impl Stream for Adapter {
-  type Item = Incoming;
-  type Error = Void;
+impl<S, K, P, H> Stream for Adapter<S, K, P, H>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        H: Handler<P::Input, P::Output>,
+{
+  type Item = P::Item;
+  type Error = AdapterError<S, K, P>;
   fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
     stream_adapter(self)
   }
 }
 
-impl ExtendedSink for Adapter {
-  type SinkItem = Outgoing;
-  type SinkError = Void;
+impl<S, K, P, H> ExtendedSink for Adapter<S, K, P, H>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        H: Handler<P::Input, P::Output>,
+{
+  type SinkItem = P::SinkItem;
+  type SinkError = AdapterError<S, K, P>;
 
   fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
     -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
   {
-    self.try_empty_buffer(task_handle)?;
-    self.buffer.push_back(item.0);
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.try_empty_buffer(task_handle)? {
+      if self.is_full() {
+        return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+      }
+    }
+    let output = self.processor.outgoing(item).map_err(Error::Processor)?;
+    self.queue(output);
     Ok(ExtendedAsyncSink::Ready)
   }
 
@@ -102,13 +395,30 @@ impl ExtendedSink for Adapter {
   {
     extended_try_ready!(self.try_empty_buffer(task_handle));
     debug_assert!(self.buffer.is_empty());
-    self.sink.extended_poll_complete(task_handle)
+    self.sink.extended_poll_complete(task_handle).map_err(Error::Sink)
+  }
+
+  /// Send everything in the buffer, close the underlying sink, and then drop
+  /// the underlying stream
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.timer = None;
+    extended_try_ready!(self.try_empty_buffer(task_handle));
+    extended_try_ready!(self.sink.extended_close(task_handle).map_err(Error::Sink));
+    self.stream = None;
+    Ok(ExtendedAsync::Ready(()))
   }
 }
 
-impl Sink for Adapter {
-  type SinkItem = Outgoing;
-  type SinkError = Void;
+impl<S, K, P, H> Sink for Adapter<S, K, P, H>
+  where S: ExtendedStream<Item=P::Input>,
+        K: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        H: Handler<P::Input, P::Output>,
+{
+  type SinkItem = P::SinkItem;
+  type SinkError = AdapterError<S, K, P>;
 
   fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
     sink_start_send_adapter(self, item)
@@ -117,20 +427,466 @@ impl Sink for Adapter {
   fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
     sink_poll_complete_adapter(self)
   }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+/// A trivial processor for byte streams, which echoes even bytes back
+/// downstream and passes odd bytes upward
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo;
+
+impl Processor for Echo {
+  type Input = u8;
+  type Output = u8;
+  type Item = u8;
+  type SinkItem = u8;
+  type Error = Void;
+
+  fn incoming(&mut self, byte: u8) -> Result<Action<u8, u8>, Void> {
+    if byte.is_multiple_of(2) {
+      Ok(Action::Reply(byte))
+    } else {
+      Ok(Action::Emit(byte))
+    }
+  }
+
+  fn outgoing(&mut self, byte: u8) -> Result<u8, Void> {
+    Ok(byte)
+  }
+}
+
+/// A message received from the exchange, deserialized from a JSON text frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incoming<T>(pub T);
+
+/// A message to send to the exchange, serialized to a JSON text frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outgoing<U>(pub U);
+
+/// What to do with a text frame from the underlying stream that can't be
+/// decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedPolicy {
+  /// Return `JsonError::Decode` from the stream
+  Fail,
+  /// Drop the frame and carry on with the next one
+  Skip,
+}
+
+/// Errors produced by the `Json` processor
+#[derive(Debug)]
+pub enum JsonError {
+  /// A frame from the underlying stream could not be decoded
+  Decode{frame: String, error: serde_json::Error},
+  /// An outgoing message could not be encoded
+  Encode(serde_json::Error),
+}
+
+impl fmt::Display for JsonError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      JsonError::Decode{ref frame, ref error} => write!(f, "failed to decode frame `{}`: {}", frame, error),
+      JsonError::Encode(ref error) => write!(f, "failed to encode message: {}", error),
+    }
+  }
+}
+
+/// A processor which decodes JSON text frames into `Incoming<T>`, and
+/// encodes `Outgoing<U>` into JSON text frames
+pub struct Json<T, U> {
+  malformed: MalformedPolicy,
+  tracer:    Tracer,
+  messages:  PhantomData<fn(U) -> T>,
+}
+
+impl<T, U> Json<T, U> {
+  pub fn new() -> Json<T, U> {
+    Json{malformed: MalformedPolicy::Fail, tracer: Tracer::none(), messages: PhantomData}
+  }
+
+  pub fn with_malformed_policy(mut self, malformed: MalformedPolicy) -> Json<T, U> {
+    self.malformed = malformed;
+    self
+  }
+
+  /// Report skipped malformed frames to `tracer`
+  pub fn with_tracer(mut self, tracer: Tracer) -> Json<T, U> {
+    self.tracer = tracer;
+    self
+  }
+}
+
+impl<T: DeserializeOwned, U: Serialize> Processor for Json<T, U> {
+  type Input = String;
+  type Output = String;
+  type Item = Incoming<T>;
+  type SinkItem = Outgoing<U>;
+  type Error = JsonError;
+
+  fn incoming(&mut self, frame: String) -> Result<Action<Incoming<T>, String>, JsonError> {
+    match serde_json::from_str(&frame) {
+      Ok(item) => Ok(Action::Emit(Incoming(item))),
+      Err(error) => match self.malformed {
+        MalformedPolicy::Fail => Err(JsonError::Decode{frame, error}),
+        MalformedPolicy::Skip => {
+          self.tracer.emit("json", Event::Skipped{reason: error.to_string()});
+          Ok(Action::Skip)
+        }
+      },
+    }
+  }
+
+  fn outgoing(&mut self, item: Outgoing<U>) -> Result<String, JsonError> {
+    serde_json::to_string(&item.0).map_err(JsonError::Encode)
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use extended::close::close;
+  use extended::mpsc::{self, Receiver, Sender};
+  use futures::future;
+
+  #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+  struct Trade {
+    price: u64,
+    size:  u64,
+  }
+
+  type TradeAdapter = Adapter<Receiver<String>, Sender<String>, Json<Trade, Trade>>;
+
+  /// An adapter which will read `frames`, and a receiver for the frames that
+  /// it writes
+  fn json_adapter(frames: &[&str], malformed: MalformedPolicy) -> (TradeAdapter, Receiver<String>) {
+    let (input, stream) = mpsc::unbounded();
+    let (sink, output) = mpsc::unbounded();
+    for frame in frames {
+      input.clone().send(frame.to_string()).wait().unwrap();
+    }
+    (Adapter::new(stream, sink, Json::new().with_malformed_policy(malformed)), output)
+  }
 
   #[test]
   fn basic() {
-    let adapter = Adapter::new();
+    let adapter = Adapter::new(
+      extended::delayed_series::Producer::new(),
+      extended::delayed_series::Consumer::new(),
+      Echo,
+    );
     let (sink, stream) = adapter.split();
-    stream
+    let _ = stream
       .take(7)
       .inspect(|x| println!("from stream: {:?}", x))
-      .map(|x| Outgoing(x.0))
       .forward(sink).wait().unwrap();
   }
+
+  #[test]
+  fn processor_actions() {
+    struct Split;
+
+    impl Processor for Split {
+      type Input = u8;
+      type Output = u8;
+      type Item = u8;
+      type SinkItem = u8;
+      type Error = Void;
+
+      fn incoming(&mut self, byte: u8) -> Result<Action<u8, u8>, Void> {
+        Ok(match byte % 4 {
+          0 => Action::Emit(byte),
+          1 => Action::Reply(byte),
+          2 => Action::Both(byte, byte + 100),
+          _ => Action::Skip,
+        })
+      }
+
+      fn outgoing(&mut self, byte: u8) -> Result<u8, Void> {
+        Ok(byte + 200)
+      }
+    }
+
+    let (input, stream) = mpsc::unbounded();
+    let (sink, output) = mpsc::unbounded();
+    for byte in 0..8 {
+      input.clone().send(byte).wait().unwrap();
+    }
+    drop(input);
+
+    let adapter = Adapter::new(stream, sink, Split);
+    let (sink, stream) = adapter.split();
+    let (stream, sink) = stream.forward(sink).wait().unwrap();
+    drop((stream, sink));
+
+    assert_eq!(output.collect().wait().unwrap(), vec![200, 1, 102, 202, 204, 5, 106, 206]);
+  }
+
+  #[test]
+  fn traced() {
+    let (input, stream) = mpsc::unbounded();
+    let (sink, output) = mpsc::unbounded();
+    for byte in &[1, 2] {
+      input.clone().send(*byte).wait().unwrap();
+    }
+    drop(input);
+
+    let (tracer, events) = extended::trace::recording();
+    let adapter = Adapter::new(stream, sink, Echo).with_tracer(tracer);
+    assert_eq!(adapter.collect().wait().unwrap(), vec![1]);
+    assert_eq!(output.collect().wait().unwrap(), vec![2]);
+
+    let events = events.lock().unwrap().iter().map(|(_, event)| event.clone()).collect::<Vec<Event>>();
+    assert_eq!(events, vec![
+      Event::Received,
+      Event::Received,
+      Event::Buffered{len: 1},
+      Event::Sent,
+      Event::Flushed,
+      Event::Dropped{outstanding: 1},
+    ]);
+  }
+
+  /// A processor which passes strings through unchanged
+  struct Strings;
+
+  impl Processor for Strings {
+    type Input = String;
+    type Output = String;
+    type Item = String;
+    type SinkItem = String;
+    type Error = Void;
+
+    fn incoming(&mut self, input: String) -> Result<Action<String, String>, Void> {
+      Ok(Action::Emit(input))
+    }
+
+    fn outgoing(&mut self, item: String) -> Result<String, Void> {
+      Ok(item)
+    }
+  }
+
+  /// A handler which answers heartbeats, acknowledges subscriptions, and
+  /// optionally sends pings
+  struct Exchange {
+    ping_interval: Option<Duration>,
+  }
+
+  impl Handler<String, String> for Exchange {
+    fn inspect(&mut self, input: &String, control: &mut Control<String>) -> bool {
+      if input == "heartbeat" {
+        let _ = control.send("heartbeat".to_string());
+        true
+      } else if input.starts_with("subscribe ") {
+        let _ = control.send(input.replace("subscribe", "subscribed"));
+        false
+      } else {
+        false
+      }
+    }
+
+    fn interval(&self) -> Option<Duration> {
+      self.ping_interval
+    }
+
+    fn tick(&mut self, control: &mut Control<String>) {
+      let _ = control.send("ping".to_string());
+    }
+  }
+
+  #[test]
+  fn handler_replies_to_control_messages() {
+    let (input, stream) = mpsc::unbounded();
+    let (sink, output) = mpsc::unbounded();
+    for frame in &["a", "heartbeat", "subscribe trades", "b", "heartbeat"] {
+      input.clone().send(frame.to_string()).wait().unwrap();
+    }
+    drop(input);
+
+    let adapter = Adapter::with_handler(stream, sink, Strings, Exchange{ping_interval: None});
+    assert_eq!(adapter.collect().wait().unwrap(), vec!["a", "subscribe trades", "b"]);
+    assert_eq!(output.collect().wait().unwrap(), vec!["heartbeat", "subscribed trades", "heartbeat"]);
+  }
+
+  #[test]
+  fn handler_sends_pings_on_a_timer() {
+    let (input, stream) = mpsc::unbounded();
+    let (sink, output) = mpsc::unbounded();
+
+    let sending = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(100));
+      input.send("done".to_string()).wait().unwrap();
+    });
+
+    let adapter = Adapter::with_handler(
+      stream,
+      sink,
+      Strings,
+      Exchange{ping_interval: Some(Duration::from_millis(10))},
+    );
+    assert_eq!(adapter.collect().wait().unwrap(), vec!["done"]);
+    sending.join().unwrap();
+
+    let pings = output.collect().wait().unwrap();
+    assert!(pings.len() >= 3, "only {} pings sent", pings.len());
+    assert!(pings.iter().all(|ping| ping == "ping"));
+  }
+
+  #[test]
+  fn close_drains_buffer_and_ends_stream() {
+    let (_input, stream) = mpsc::unbounded::<String>();
+    let (sink, output) = mpsc::unbounded();
+    let mut adapter = Adapter::new(stream, sink, Strings);
+
+    future::lazy(|| {
+      for item in &["a", "b", "c"] {
+        assert!(sink_start_send_adapter(&mut adapter, item.to_string()).unwrap().is_ready());
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+
+    let adapter = close(adapter).wait().unwrap();
+    let (next, adapter) = adapter.into_future().map_err(|(err, _)| err).wait().unwrap();
+    assert_eq!(next, None);
+    drop(adapter);
+
+    assert_eq!(output.collect().wait().unwrap(), vec!["a", "b", "c"]);
+  }
+
+  #[test]
+  fn capacity_bounds_buffer_against_slow_sink() {
+    let mut core = Core::new().unwrap();
+    let mut adapter = Adapter::new(
+      extended::instant_series::Producer::new(),
+      extended::delayed_series::Consumer::new(),
+      Echo,
+    ).with_capacity(2);
+    let mut deadline = Sleeper::new(Duration::from_millis(2500));
+    let mut most_buffered = 0;
+    let mut emitted = 0;
+
+    core.run(future::poll_fn(|| {
+      while let Async::Ready(item) = adapter.poll().unwrap() {
+        assert!(item.is_some());
+        emitted += 1;
+        most_buffered = most_buffered.max(adapter.queued());
+      }
+      most_buffered = most_buffered.max(adapter.queued());
+      deadline.poll()
+    })).unwrap();
+
+    assert_eq!(most_buffered, 2);
+    // only the few odd bytes that arrive between the slow sink's sends get
+    // through, instead of the unbounded number an instant producer makes
+    assert!(emitted < 100, "{} items emitted", emitted);
+  }
+
+  /// A handler which answers every item with a burst of replies
+  struct Flood {
+    refused: usize,
+  }
+
+  impl Handler<u8, u8> for Flood {
+    fn inspect(&mut self, input: &u8, control: &mut Control<u8>) -> bool {
+      for _ in 0..10 {
+        if control.send(*input).is_err() {
+          self.refused += 1;
+        }
+      }
+      false
+    }
+  }
+
+  #[test]
+  fn capacity_bounds_handler_replies() {
+    let mut core = Core::new().unwrap();
+    let mut adapter = Adapter::with_handler(
+      extended::instant_series::Producer::new(),
+      extended::delayed_series::Consumer::new(),
+      Echo,
+      Flood{refused: 0},
+    ).with_capacity(3);
+    let mut deadline = Sleeper::new(Duration::from_millis(1500));
+    let mut most_buffered = 0;
+
+    core.run(future::poll_fn(|| {
+      while let Async::Ready(item) = adapter.poll().unwrap() {
+        assert!(item.is_some());
+        most_buffered = most_buffered.max(adapter.queued());
+      }
+      most_buffered = most_buffered.max(adapter.queued());
+      deadline.poll()
+    })).unwrap();
+
+    assert_eq!(most_buffered, 3);
+    assert!(adapter.handler.refused > 0);
+  }
+
+  #[test]
+  fn json_round_trip() {
+    let (adapter, output) = json_adapter(
+      &[
+        r#"{"price": 100, "size": 1}"#,
+        r#"{"price": 101, "size": 2}"#,
+        r#"{"price": 102, "size": 3}"#,
+      ],
+      MalformedPolicy::Fail,
+    );
+    let (sink, stream) = adapter.split();
+    let _ = stream
+      .map(|Incoming(trade)| Outgoing(Trade{price: trade.price * 2, ..trade}))
+      .forward(sink).wait().unwrap();
+
+    assert_eq!(
+      output.collect().wait().unwrap(),
+      vec![
+        r#"{"price":200,"size":1}"#,
+        r#"{"price":202,"size":2}"#,
+        r#"{"price":204,"size":3}"#,
+      ]
+    );
+  }
+
+  #[test]
+  fn malformed_frames_are_errors_by_default() {
+    let (adapter, _output) = json_adapter(&[r#"{"price": 100, "size": 1}"#, "garbage"], MalformedPolicy::Fail);
+    let (first, adapter) = adapter.into_future().map_err(|(err, _)| err).wait().unwrap();
+    assert_eq!(first, Some(Incoming(Trade{price: 100, size: 1})));
+    match adapter.into_future().wait() {
+      Err((Error::Processor(JsonError::Decode{frame, ..}), _)) => assert_eq!(frame, "garbage"),
+      Err((error, _)) => panic!("unexpected error: {:?}", error),
+      Ok((item, _)) => panic!("expected decode error, got {:?}", item),
+    }
+  }
+
+  #[test]
+  fn malformed_frames_can_be_skipped() {
+    let (adapter, _output) = json_adapter(
+      &[
+        r#"{"price": 100, "size": 1}"#,
+        r#"{"price": "a lot"}"#,
+        "garbage",
+        r#"{"price": 101, "size": 2}"#,
+      ],
+      MalformedPolicy::Skip,
+    );
+    let trades = adapter
+      .map(|Incoming(trade)| trade)
+      .collect().wait().unwrap();
+    assert_eq!(trades, vec![Trade{price: 100, size: 1}, Trade{price: 101, size: 2}]);
+  }
+
+  #[test]
+  fn stream_end_sends_pending_replies() {
+    let recorder = extended::recorder::Recorder::new();
+    let recording = recorder.recording();
+    let stream = extended::instant_series::Producer::from_items(0..10).end_after(6);
+    let mut adapter = Stream::wait(Adapter::new(stream, recorder, Echo));
+
+    assert_eq!(adapter.by_ref().map(Result::unwrap).collect::<Vec<u8>>(), vec![1, 3, 5]);
+    recording.assert_received_in_order(&[0, 2, 4]);
+    assert!(adapter.next().is_none(), "adapter should stay ended");
+  }
 }
@@ -0,0 +1,254 @@
+use common::*;
+use extended::common::*;
+use extended::sleeper::Sleeper;
+use extended::trace::{Event, Tracer};
+
+use std::mem;
+
+/// A sink which groups items into `Vec` batches for an inner sink, such as
+/// a database writer, which is more efficient with many items at once
+///
+/// A batch is written when it reaches `max_items`, or when `max_age` has
+/// passed since its first item arrived, whichever comes first. While a
+/// batch is being written the next one keeps filling, but once it is full
+/// `start_send` returns `NotReady` until the write completes.
+///
+/// `poll_complete` only writes a partial batch once it is old enough, so
+/// calling it does not defeat batching. `close` writes whatever is left
+/// immediately.
+pub struct BatchSink<S: ExtendedSink> {
+  inner:     S,
+  batch:     S::SinkItem,
+  max_items: usize,
+  max_age:   Duration,
+  /// when the current batch must be written
+  deadline:  Option<Instant>,
+  /// a batch the inner sink has not accepted yet
+  unsent:    Option<S::SinkItem>,
+  /// a batch has been handed to the inner sink, but not yet completed
+  writing:   bool,
+  tracer:    Tracer,
+}
+
+impl<S, T> BatchSink<S> where S: ExtendedSink<SinkItem=Vec<T>> {
+  pub fn new(inner: S, max_items: usize, max_age: Duration) -> BatchSink<S> {
+    assert!(max_items > 0, "batches must hold at least one item");
+    BatchSink {
+      inner,
+      batch:    Vec::with_capacity(max_items),
+      max_items,
+      max_age,
+      deadline: None,
+      unsent:   None,
+      writing:  false,
+      tracer:   Tracer::none(),
+    }
+  }
+
+  /// Report items being batched and batches being written to `tracer`
+  pub fn with_tracer(self, tracer: Tracer) -> BatchSink<S> {
+    BatchSink{tracer, ..self}
+  }
+
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+
+  fn due(&self) -> bool {
+    !self.batch.is_empty()
+      && (self.batch.len() >= self.max_items || self.deadline.is_some_and(|deadline| Instant::now() >= deadline))
+  }
+
+  fn push(&mut self, item: T) {
+    if self.batch.is_empty() {
+      self.deadline = Some(Instant::now() + self.max_age);
+    }
+    self.batch.push(item);
+    self.tracer.emit("batch", Event::Buffered{len: self.batch.len()});
+  }
+
+  /// Hand the current batch to the inner sink, and start a new one
+  fn start_write(&mut self) {
+    debug_assert!(!self.writing);
+    let batch = mem::replace(&mut self.batch, Vec::with_capacity(self.max_items));
+    self.unsent = Some(batch);
+    self.writing = true;
+    self.deadline = None;
+  }
+
+  /// Drive the batch being written, if any, to completion
+  fn drive_write(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, S::SinkError> {
+    if let Some(batch) = self.unsent.take() {
+      if let ExtendedAsyncSink::NotReady(batch, agreement_to_notify)
+        = self.inner.extended_start_send(task_handle, batch)?
+      {
+        self.unsent = Some(batch);
+        return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+      }
+      self.tracer.emit("batch", Event::Sent);
+    }
+
+    if self.writing {
+      extended_try_ready!(self.inner.extended_poll_complete(task_handle));
+      self.writing = false;
+      self.tracer.emit("batch", Event::Flushed);
+    }
+
+    Ok(ExtendedAsync::Ready(()))
+  }
+
+  /// Drive the current write, then start writing the current batch if it is
+  /// due, returning `NotReady` if a write is still in flight afterwards
+  fn write_if_due(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, S::SinkError> {
+    extended_try_ready!(self.drive_write(task_handle));
+    if self.due() {
+      self.start_write();
+      return self.drive_write(task_handle);
+    }
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<S, T> ExtendedSink for BatchSink<S> where S: ExtendedSink<SinkItem=Vec<T>> {
+  type SinkItem = T;
+  type SinkError = S::SinkError;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.write_if_due(task_handle)? {
+      if self.batch.len() >= self.max_items {
+        return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+      }
+    }
+
+    self.push(item);
+
+    // start writing a full batch straight away, and let `poll_complete`
+    // drive it from here
+    if !self.writing && self.due() {
+      self.start_write();
+      self.drive_write(task_handle)?;
+    }
+
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    loop {
+      extended_try_ready!(self.write_if_due(task_handle));
+
+      let deadline = match self.deadline {
+        Some(deadline) if !self.batch.is_empty() => deadline,
+        _ => return Ok(ExtendedAsync::Ready(())),
+      };
+
+      // wait for the batch to come due
+      let now = Instant::now();
+      if deadline > now {
+        extended_try_ready!(Sleeper::new(deadline - now).with_tracer(self.tracer.clone())
+          .extended_poll(task_handle)
+          .map_err(|v| match v {}));
+      }
+    }
+  }
+
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    extended_try_ready!(self.drive_write(task_handle));
+    if !self.batch.is_empty() {
+      self.start_write();
+      extended_try_ready!(self.drive_write(task_handle));
+    }
+    self.inner.extended_close(task_handle)
+  }
+}
+
+impl<S, T> Sink for BatchSink<S> where S: ExtendedSink<SinkItem=Vec<T>> {
+  type SinkItem = T;
+  type SinkError = S::SinkError;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::instant_series::Producer;
+  use extended::recorder::Recorder;
+  use futures::{future, stream};
+  use recording::Schedule;
+
+  #[test]
+  fn flush_on_size() {
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let sink = BatchSink::new(recorder, 4, Duration::from_secs(60));
+
+    let _ = Producer::from_items(0..10).forward(sink.sink_map_err(|v| match v {})).wait().unwrap();
+
+    // the last, partial batch is written when forward closes the sink
+    recording.assert_received_in_order(&[vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+  }
+
+  #[test]
+  fn flush_on_time() {
+    let start = Instant::now();
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let sink = BatchSink::new(recorder, 10, Duration::from_millis(100));
+
+    let late = Sleeper::new(Duration::from_millis(400)).map(|()| 3).into_stream();
+    let items = stream::iter_ok::<_, Void>(0..3).chain(late);
+    let _ = items.forward(sink.sink_map_err(|v| match v {})).wait().unwrap();
+
+    recording.assert_received_in_order(&[vec![0, 1, 2], vec![3]]);
+    let arrivals = recording.arrivals();
+    assert!(arrivals[0] - start >= Duration::from_millis(100));
+    assert!(arrivals[0] - start < Duration::from_millis(300));
+    assert!(arrivals[1] - start >= Duration::from_millis(400));
+  }
+
+  #[test]
+  fn backpressure_while_writing() {
+    // the inner sink never accepts a batch, so the first write never ends
+    let recorder = Recorder::new().with_schedule(Schedule::new(vec![true]));
+    let mut sink = BatchSink::new(recorder, 2, Duration::from_secs(60));
+
+    future::lazy(|| {
+      for item in 0..4 {
+        assert_eq!(sink.start_send(item), Ok(AsyncSink::Ready));
+      }
+      assert_eq!(sink.start_send(4), Ok(AsyncSink::NotReady(4)));
+      assert_eq!(sink.poll_complete(), Ok(Async::NotReady));
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+
+    assert_eq!(sink.batch, vec![2, 3]);
+    assert_eq!(sink.unsent, Some(vec![0, 1]));
+  }
+
+  #[test]
+  fn traced() {
+    let (tracer, events) = extended::trace::recording();
+    let sink = BatchSink::new(Recorder::new(), 2, Duration::from_secs(60)).with_tracer(tracer);
+
+    let _ = Producer::from_items(0..2).forward(sink.sink_map_err(|v| match v {})).wait().unwrap();
+
+    let events = events.lock().unwrap().iter().map(|(_, event)| event.clone()).collect::<Vec<Event>>();
+    assert_eq!(events, vec![Event::Buffered{len: 1}, Event::Buffered{len: 2}, Event::Sent, Event::Flushed]);
+  }
+}
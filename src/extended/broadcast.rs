@@ -0,0 +1,308 @@
+use common::*;
+use extended::common::*;
+
+/// The error produced by a `Receiver` which fell so far behind that values
+/// were overwritten before it could receive them, containing the number of
+/// values that it missed
+///
+/// The receiver may continue to be polled after this error, and will resume
+/// with the oldest value that is still retained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl fmt::Display for Lagged {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "broadcast receiver lagged by {} values", self.0)
+  }
+}
+
+/// The error produced when sending to a channel with no receivers, containing
+/// the value that could not be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "broadcast channel has no receivers")
+  }
+}
+
+struct State<T> {
+  buffer:    VecDeque<T>,
+  capacity:  usize,
+  /// the total number of values ever sent, and thus the sequence number of
+  /// the next value
+  sent:      u64,
+  senders:   usize,
+  receivers: usize,
+  next_id:   usize,
+  waiting:   HashMap<usize, Task>,
+}
+
+impl<T> State<T> {
+  fn oldest(&self) -> u64 {
+    self.sent - self.buffer.len() as u64
+  }
+
+  fn subscribe(&mut self) -> (usize, u64) {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.receivers += 1;
+    (id, self.sent)
+  }
+
+  fn wake_all(&mut self) -> Vec<Task> {
+    self.waiting.drain().map(|(_, task)| task).collect()
+  }
+}
+
+/// Create a channel which delivers every sent value to every receiver,
+/// retaining up to `capacity` values for receivers which have fallen behind
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(capacity > 0, "broadcast channel capacity must be greater than zero");
+
+  let mut state = State {
+    buffer:    VecDeque::with_capacity(capacity),
+    capacity,
+    sent:      0,
+    senders:   1,
+    receivers: 0,
+    next_id:   0,
+    waiting:   HashMap::new(),
+  };
+
+  let (id, next) = state.subscribe();
+  let state = Arc::new(Mutex::new(state));
+
+  (Sender{state: state.clone()}, Receiver{state, id, next})
+}
+
+/// The sending half of a broadcast channel
+///
+/// Sending never waits. If a receiver falls more than `capacity` values
+/// behind, the oldest values are overwritten and that receiver will see a
+/// `Lagged` error.
+pub struct Sender<T> {
+  state: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+  /// Send `value` to all current receivers, returning the number of receivers
+  /// it was sent to
+  pub fn broadcast(&self, value: T) -> Result<usize, SendError<T>> {
+    let (receivers, tasks) = {
+      let mut state = self.state.lock().unwrap();
+      if state.receivers == 0 {
+        return Err(SendError(value));
+      }
+      if state.buffer.len() == state.capacity {
+        state.buffer.pop_front();
+      }
+      state.buffer.push_back(value);
+      state.sent += 1;
+      (state.receivers, state.wake_all())
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+
+    Ok(receivers)
+  }
+
+  /// Create a new receiver which will receive all values sent after this call
+  pub fn subscribe(&self) -> Receiver<T> {
+    let (id, next) = self.state.lock().unwrap().subscribe();
+    Receiver{state: self.state.clone(), id, next}
+  }
+
+  pub fn receiver_count(&self) -> usize {
+    self.state.lock().unwrap().receivers
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    self.state.lock().unwrap().senders += 1;
+    Sender{state: self.state.clone()}
+  }
+}
+
+impl<T: Clone> ExtendedSink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = SendError<T>;
+
+  fn extended_start_send(&mut self, _task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    self.broadcast(item)?;
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, _task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<T: Clone> Sink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = SendError<T>;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let tasks = {
+      let mut state = self.state.lock().unwrap();
+      state.senders -= 1;
+      if state.senders == 0 {
+        state.wake_all()
+      } else {
+        Vec::new()
+      }
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+}
+
+/// The receiving half of a broadcast channel, a stream of every value sent
+/// after it was created, which ends once every sender has been dropped
+pub struct Receiver<T> {
+  state: Arc<Mutex<State<T>>>,
+  id:    usize,
+  next:  u64,
+}
+
+impl<T: Clone> ExtendedStream for Receiver<T> {
+  type Item = T;
+  type Error = Lagged;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    let mut state = self.state.lock().unwrap();
+    let oldest = state.oldest();
+
+    if self.next < oldest {
+      let missed = oldest - self.next;
+      self.next = oldest;
+      Err(Lagged(missed))
+    } else if self.next < state.sent {
+      let value = state.buffer[(self.next - oldest) as usize].clone();
+      self.next += 1;
+      Ok(ExtendedAsync::Ready(Some(value)))
+    } else if state.senders == 0 {
+      Ok(ExtendedAsync::Ready(None))
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.waiting.insert(self.id, task);
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+  type Item = T;
+  type Error = Lagged;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.state.lock().unwrap();
+    state.receivers -= 1;
+    state.waiting.remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::stream;
+
+  #[test]
+  fn every_receiver_sees_every_value() {
+    let (sender, first) = channel(16);
+    let second = sender.subscribe();
+
+    for i in 0..10u8 {
+      assert_eq!(sender.broadcast(i), Ok(2));
+    }
+    drop(sender);
+
+    let expected = (0..10).collect::<Vec<u8>>();
+    assert_eq!(first.collect().wait(), Ok(expected.clone()));
+    assert_eq!(second.collect().wait(), Ok(expected));
+  }
+
+  #[test]
+  fn receivers_only_see_values_sent_after_subscribing() {
+    let (sender, _first) = channel(16);
+    sender.broadcast(0u8).unwrap();
+    let second = sender.subscribe();
+    sender.broadcast(1).unwrap();
+    drop(sender);
+    assert_eq!(second.collect().wait(), Ok(vec![1]));
+  }
+
+  #[test]
+  fn lagging_receiver_is_told_how_far_behind_it_is() {
+    let (sender, receiver) = channel(4);
+    for i in 0..10u8 {
+      sender.broadcast(i).unwrap();
+    }
+    drop(sender);
+
+    let mut receiver = receiver.wait();
+    assert_eq!(receiver.next(), Some(Err(Lagged(6))));
+    let rest = receiver.collect::<Result<Vec<u8>, Lagged>>();
+    assert_eq!(rest, Ok(vec![6, 7, 8, 9]));
+  }
+
+  #[test]
+  fn send_without_receivers() {
+    let (sender, receiver) = channel(4);
+    drop(receiver);
+    assert_eq!(sender.broadcast(7u8), Err(SendError(7)));
+  }
+
+  #[test]
+  fn fan_out_on_core() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (sender, receiver) = channel(4);
+    let receivers = vec![receiver, sender.subscribe(), sender.subscribe()];
+
+    handle.spawn(
+      extended::delayed_series::Producer::new()
+        .take(3)
+        .map_err(|err| -> SendError<u8> { match err {} })
+        .forward(sender)
+        .map(|_| ())
+        .map_err(|err| panic!("got error: {}", err))
+    );
+
+    let collected = core.run(stream::futures_unordered(receivers.into_iter().map(|receiver| receiver.collect()))
+      .collect()).unwrap();
+
+    assert_eq!(collected.len(), 3);
+    assert_eq!(collected[0].len(), 3);
+    assert_eq!(collected[0], collected[1]);
+    assert_eq!(collected[1], collected[2]);
+  }
+}
@@ -1,21 +1,74 @@
 use common::*;
 use extended::common::*;
+use extended::trace::{Event, Tracer};
+use overflow::{Buffer, Overflow};
+
+/// A sink that consumes one item every second, by passing it to a callback
+/// which by default discards it, but which can buffer up to `capacity`
+/// items, applying an `Overflow` policy when the buffer is full
+///
+/// Buffered items can instead be sent on to any inner sink, whose errors are
+/// passed through.
+pub struct Consumer<K = extended::delayed_series::Consumer<u8, fn(u8)>> {
+  buffer: Buffer,
+  inner:  K,
+  tracer: Tracer,
+}
 
-const BUFFER_CAPACITY: usize = 10;
+impl Consumer {
+  /// A consumer which applies backpressure when its buffer is full
+  pub fn new(capacity: usize) -> Consumer {
+    Consumer::with_callback(capacity, drop)
+  }
+}
 
-/// A sink that consumes one item every second, but which can buffer up to
-/// BUFFER_SIZE items
-pub struct Consumer {
-  buffer: VecDeque<u8>,
-  inner:  extended::delayed_series::Consumer,
+impl<F: FnMut(u8)> Consumer<extended::delayed_series::Consumer<u8, F>> {
+  /// A consumer which passes each item to `callback`
+  pub fn with_callback(capacity: usize, callback: F) -> Consumer<extended::delayed_series::Consumer<u8, F>> {
+    Consumer::with_inner(capacity, extended::delayed_series::Consumer::with_callback(callback))
+  }
 }
 
-impl Consumer {
-  pub fn new() -> Consumer {
-    Consumer{buffer: VecDeque::with_capacity(BUFFER_CAPACITY + 1), inner: extended::delayed_series::Consumer::new()}
+impl<K: ExtendedSink<SinkItem=u8>> Consumer<K> {
+  /// A consumer which sends buffered items on to `inner`
+  pub fn with_inner(capacity: usize, inner: K) -> Consumer<K> {
+    Consumer {
+      buffer: Buffer::new(capacity, Overflow::Block),
+      inner,
+      tracer: Tracer::none(),
+    }
+  }
+
+  /// Apply `overflow` when the buffer is full
+  pub fn with_overflow(mut self, overflow: Overflow) -> Consumer<K> {
+    self.buffer = Buffer::new(self.buffer.capacity(), overflow);
+    self
   }
 
-  fn try_empty_buffer(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, Void> {
+  /// Report items being buffered, sent and flushed to `tracer`
+  pub fn with_tracer(mut self, tracer: Tracer) -> Consumer<K> {
+    self.tracer = tracer;
+    self
+  }
+
+  /// The number of items dropped or replaced because the buffer was full
+  pub fn dropped(&self) -> u64 {
+    self.buffer.dropped()
+  }
+
+  /// Offer `item` to the buffer, returning it if it was refused
+  fn offer(&mut self, item: u8) -> Result<(), u8> {
+    let dropped = self.buffer.offer(item)?;
+    if dropped > 0 {
+      self.tracer.emit("buffered", Event::Discarded{count: dropped as usize});
+    } else {
+      self.tracer.emit("buffered", Event::Buffered{len: self.buffer.len()});
+    }
+    Ok(())
+  }
+
+  fn try_empty_buffer(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, K::SinkError> {
+    let mut sent = false;
     while let Some(item) = self.buffer.pop_front() {
       if let ExtendedAsyncSink::NotReady(item, agreement_to_notify)
         = self.inner.extended_start_send(task_handle, item)?
@@ -27,29 +80,39 @@ impl Consumer {
 
         return Ok(ExtendedAsync::NotReady(agreement_to_notify));
       }
+      self.tracer.emit("buffered", Event::Sent);
+      sent = true;
+    }
+
+    if sent {
+      self.tracer.emit("buffered", Event::Flushed);
     }
 
     Ok(ExtendedAsync::Ready(()))
   }
 }
 
-impl ExtendedSink for Consumer {
+impl<K> Drop for Consumer<K> {
+  fn drop(&mut self) {
+    self.tracer.emit("buffered", Event::Dropped{outstanding: self.buffer.len() as u64});
+  }
+}
+
+impl<K: ExtendedSink<SinkItem=u8>> ExtendedSink for Consumer<K> {
   type SinkItem = u8;
-  type SinkError = Void;
+  type SinkError = K::SinkError;
 
   fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
     -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
   {
     if let ExtendedAsync::NotReady(agreement_to_notify) = self.try_empty_buffer(task_handle)? {
-      if self.buffer.len() < BUFFER_CAPACITY {
-        self.buffer.push_back(item);
-        Ok(ExtendedAsyncSink::Ready)
-      } else {
-        Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify))
+      match self.offer(item) {
+        Ok(()) => Ok(ExtendedAsyncSink::Ready),
+        Err(item) => Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify)),
       }
     } else {
-      assert!(self.buffer.len() < BUFFER_CAPACITY);
-      self.buffer.push_back(item);
+      assert!(self.buffer.is_empty());
+      self.offer(item).expect("empty buffer refused an item");
       Ok(ExtendedAsyncSink::Ready)
     }
   }
@@ -63,9 +126,9 @@ impl ExtendedSink for Consumer {
   }
 }
 
-impl Sink for Consumer {
+impl<K: ExtendedSink<SinkItem=u8>> Sink for Consumer<K> {
   type SinkItem = u8;
-  type SinkError = Void;
+  type SinkError = K::SinkError;
 
   fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
     sink_start_send_adapter(self, item)
@@ -74,23 +137,71 @@ impl Sink for Consumer {
   fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
     sink_poll_complete_adapter(self)
   }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use recording::Recording;
+  use overflow::offer_all;
 
   #[test]
   fn production_and_consumption_are_concurrent() {
     let mut core = Core::new().unwrap();
-    let start = Instant::now();
-    let producer = extended::delayed_series::Producer::new().take(5);
-    let consumer = Consumer::new();
+    let recording = Recording::new();
+    let producer = extended::delayed_series::Producer::from_items(0..5);
+    let consumer = Consumer::with_callback(10, recording.callback());
+
+    let _ = core.run(producer.forward(consumer)).unwrap();
 
-    core.run(producer.forward(consumer)).unwrap();
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
+  }
 
-    let elapsed = start.elapsed();
-    assert!(elapsed < Duration::new(6, 500_000_000));
-    assert!(elapsed > Duration::new(5, 500_000_000));
+  #[test]
+  fn traced() {
+    let (tracer, events) = extended::trace::recording();
+    let producer = extended::instant_series::Producer::new().take(2);
+    let consumer = Consumer::new(10).with_tracer(tracer);
+
+    let _ = producer.forward(consumer).wait().unwrap();
+
+    let events = events.lock().unwrap().iter().map(|(_, event)| event.clone()).collect::<Vec<Event>>();
+    assert_eq!(events[0], Event::Buffered{len: 1});
+    assert_eq!(events.iter().filter(|event| **event == Event::Sent).count(), 2);
+    assert!(events.contains(&Event::Flushed));
+    assert_eq!(events.last(), Some(&Event::Dropped{outstanding: 0}));
+  }
+
+  #[test]
+  fn stream_end_drains_buffer() {
+    let recording = Recording::new();
+    let producer = extended::instant_series::Producer::from_items(0..).end_after(3);
+    let consumer = Consumer::with_callback(10, recording.callback());
+
+    // forward closes the consumer at stream end, which waits for the buffer
+    let _ = producer.forward(consumer).wait().unwrap();
+
+    recording.assert_received_in_order(&[0, 1, 2]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
+  }
+
+  #[test]
+  fn drops_are_traced() {
+    let (tracer, events) = extended::trace::recording();
+    let mut consumer = Consumer::new(1).with_overflow(Overflow::DropNewest).with_tracer(tracer);
+    offer_all(&mut consumer, 0..3);
+    assert_eq!(consumer.dropped(), 1);
+    drop(consumer);
+
+    let events = events.lock().unwrap().iter().map(|(_, event)| event.clone()).collect::<Vec<Event>>();
+    assert!(events.contains(&Event::Discarded{count: 1}));
+    assert_eq!(events.last(), Some(&Event::Dropped{outstanding: 1}));
   }
 }
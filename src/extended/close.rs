@@ -0,0 +1,117 @@
+use common::*;
+use extended::common::*;
+use extended::sleeper::Sleeper;
+
+/// The error produced when closing a sink fails
+#[derive(Debug)]
+pub enum CloseError<E> {
+  /// The sink failed while closing
+  Sink(E),
+  /// The sink did not finish closing before the timeout
+  TimedOut,
+}
+
+impl<E: fmt::Display> fmt::Display for CloseError<E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      CloseError::Sink(ref error) => write!(f, "sink failed while closing: {}", error),
+      CloseError::TimedOut => write!(f, "timed out while closing sink"),
+    }
+  }
+}
+
+/// Close `sink`, resolving to the closed sink once everything it has
+/// buffered has been drained
+pub fn close<K: ExtendedSink>(sink: K) -> Close<K> {
+  Close{sink: Some(sink), deadline: None}
+}
+
+/// Close `sink`, giving up and dropping it if it has not been drained after
+/// `timeout`
+pub fn close_with_timeout<K: ExtendedSink>(sink: K, timeout: Duration) -> Close<K> {
+  Close{sink: Some(sink), deadline: Some(Sleeper::new(timeout))}
+}
+
+/// A future which drives a sink's `extended_close` to completion
+pub struct Close<K> {
+  sink:     Option<K>,
+  deadline: Option<Sleeper>,
+}
+
+impl<K: ExtendedSink> ExtendedFuture for Close<K> {
+  type Item = K;
+  type Error = CloseError<K::SinkError>;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let agreement_to_notify = match self.sink.as_mut()
+      .expect("close polled after completion")
+      .extended_close(task_handle)
+      .map_err(CloseError::Sink)?
+    {
+      ExtendedAsync::Ready(()) => return Ok(ExtendedAsync::Ready(self.sink.take().unwrap())),
+      ExtendedAsync::NotReady(agreement_to_notify) => agreement_to_notify,
+    };
+
+    if let Some(ref mut deadline) = self.deadline {
+      if let Ok(ExtendedAsync::Ready(())) = deadline.extended_poll(task_handle) {
+        self.sink = None;
+        return Err(CloseError::TimedOut);
+      }
+    }
+
+    Ok(ExtendedAsync::NotReady(agreement_to_notify))
+  }
+}
+
+impl<K: ExtendedSink> Future for Close<K> {
+  type Item = K;
+  type Error = CloseError<K::SinkError>;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::buffered::Consumer;
+  use futures::future;
+  use recording::Recording;
+
+  /// A buffered consumer with `n` items waiting in its buffer, which records
+  /// the items it consumes in `recording`
+  fn loaded_consumer(n: u8, recording: &Recording<u8>) -> Consumer<impl ExtendedSink<SinkItem=u8, SinkError=Void>> {
+    let mut consumer = Consumer::with_callback(10, recording.callback());
+    future::lazy(|| {
+      for i in 0..n {
+        assert!(sink_start_send_adapter(&mut consumer, i).unwrap().is_ready());
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+    consumer
+  }
+
+  #[test]
+  fn close_waits_for_drain() {
+    let recording = Recording::new();
+    close(loaded_consumer(2, &recording)).wait().unwrap();
+    recording.assert_received_in_order(&[0, 1]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+  }
+
+  #[test]
+  fn close_times_out() {
+    let start = Instant::now();
+    match close_with_timeout(loaded_consumer(5, &Recording::new()), Duration::from_millis(100)).wait() {
+      Err(CloseError::TimedOut) => {}
+      other => panic!("expected timeout, got {:?}", other.map(|_| ())),
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+  }
+
+  #[test]
+  fn close_within_timeout() {
+    close_with_timeout(loaded_consumer(1, &Recording::new()), Duration::from_secs(5)).wait().unwrap();
+  }
+}
@@ -0,0 +1,407 @@
+use common::*;
+use extended::common::*;
+
+/// The number of bytes to attempt to read from the underlying IO object at a
+/// time
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// The default number of bytes that may be waiting in a `Framed`'s write
+/// buffer before it stops accepting new items
+const DEFAULT_HIGH_WATER_MARK: usize = 8 * 1024;
+
+/// Decodes frames from a buffer of bytes
+pub trait ExtendedDecoder {
+  type Item;
+  type Error: From<io::Error>;
+
+  /// Attempt to decode a frame from the front of `buffer`, removing the bytes
+  /// that it occupied. Returns `None` if `buffer` does not yet contain a
+  /// complete frame.
+  fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error>;
+
+  /// Called in place of `decode` once the end of the input has been reached.
+  /// By default, decodes any remaining frames and then fails if any bytes are
+  /// left over.
+  fn decode_eof(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error> {
+    match self.decode(buffer)? {
+      Some(frame) => Ok(Some(frame)),
+      None => if buffer.is_empty() {
+        Ok(None)
+      } else {
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bytes remaining at end of input").into())
+      },
+    }
+  }
+}
+
+/// Encodes frames into a buffer of bytes
+pub trait ExtendedEncoder {
+  type Item;
+  type Error: From<io::Error>;
+
+  /// Append the encoded form of `item` to `buffer`
+  fn encode(&mut self, item: Self::Item, buffer: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// A `Sink + Stream` of frames, layered on an underlying byte stream with an
+/// `ExtendedDecoder` and `ExtendedEncoder`
+///
+/// Bytes are read into a read buffer until the decoder can produce a frame.
+/// Items are encoded into a write buffer, which is written out by
+/// `extended_poll_complete`. Once the write buffer has grown past its high
+/// water mark, `extended_start_send` will try to write it out, and will apply
+/// backpressure if it can't.
+pub struct Framed<IO, C> {
+  io:              IO,
+  codec:           C,
+  read_buffer:     Vec<u8>,
+  write_buffer:    Vec<u8>,
+  eof:             bool,
+  high_water_mark: usize,
+}
+
+impl<IO, C> Framed<IO, C> {
+  pub fn new(io: IO, codec: C) -> Framed<IO, C> {
+    Framed::from_parts(io, codec, Vec::new())
+  }
+
+  /// Create a `Framed` whose read buffer starts with `read_buffer`, for
+  /// example, bytes which were read from `io` by a protocol handshake but
+  /// that belong to the first frame
+  pub fn from_parts(io: IO, codec: C, read_buffer: Vec<u8>) -> Framed<IO, C> {
+    Framed {
+      io,
+      codec,
+      read_buffer,
+      write_buffer:    Vec::new(),
+      eof:             false,
+      high_water_mark: DEFAULT_HIGH_WATER_MARK,
+    }
+  }
+
+  /// Set the number of bytes that may be waiting in the write buffer before
+  /// new items are refused
+  pub fn with_high_water_mark(mut self, high_water_mark: usize) -> Framed<IO, C> {
+    self.high_water_mark = high_water_mark;
+    self
+  }
+
+  pub fn get_ref(&self) -> &IO {
+    &self.io
+  }
+
+  pub fn get_mut(&mut self) -> &mut IO {
+    &mut self.io
+  }
+
+  pub fn codec_mut(&mut self) -> &mut C {
+    &mut self.codec
+  }
+
+  pub fn into_inner(self) -> IO {
+    self.io
+  }
+}
+
+impl<IO: ExtendedWrite, C> Framed<IO, C> {
+  fn try_empty_write_buffer(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<(), io::Error> {
+    while !self.write_buffer.is_empty() {
+      let n = extended_try_ready!(self.io.extended_write(task_handle, &self.write_buffer));
+      if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame to underlying io"));
+      }
+      self.write_buffer.drain(..n);
+    }
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<IO: ExtendedRead, C: ExtendedDecoder> ExtendedStream for Framed<IO, C> {
+  type Item = C::Item;
+  type Error = C::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    loop {
+      if self.eof {
+        return Ok(ExtendedAsync::Ready(self.codec.decode_eof(&mut self.read_buffer)?));
+      }
+
+      if let Some(frame) = self.codec.decode(&mut self.read_buffer)? {
+        return Ok(ExtendedAsync::Ready(Some(frame)));
+      }
+
+      let mut chunk = [0; READ_CHUNK_SIZE];
+      match extended_try_ready!(self.io.extended_read(task_handle, &mut chunk).map_err(C::Error::from)) {
+        0 => self.eof = true,
+        n => self.read_buffer.extend_from_slice(&chunk[..n]),
+      }
+    }
+  }
+}
+
+impl<IO: ExtendedRead, C: ExtendedDecoder> Stream for Framed<IO, C> {
+  type Item = C::Item;
+  type Error = C::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<IO: ExtendedWrite, C: ExtendedEncoder> ExtendedSink for Framed<IO, C> {
+  type SinkItem = C::Item;
+  type SinkError = C::Error;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if self.write_buffer.len() >= self.high_water_mark {
+      if let ExtendedAsync::NotReady(agreement_to_notify) = self.try_empty_write_buffer(task_handle)? {
+        if self.write_buffer.len() >= self.high_water_mark {
+          return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+        }
+      }
+    }
+
+    self.codec.encode(item, &mut self.write_buffer)?;
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    extended_try_ready!(self.try_empty_write_buffer(task_handle).map_err(C::Error::from));
+    Ok(self.io.extended_flush(task_handle)?)
+  }
+}
+
+impl<IO: ExtendedWrite, C: ExtendedEncoder> Sink for Framed<IO, C> {
+  type SinkItem = C::Item;
+  type SinkError = C::Error;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+}
+
+/// A codec for newline-delimited UTF-8 text
+///
+/// Decoded lines do not include the trailing `\n` or `\r\n`. A final line
+/// without a newline is returned at the end of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinesCodec {
+  _private: (),
+}
+
+impl LinesCodec {
+  pub fn new() -> LinesCodec {
+    LinesCodec{_private: ()}
+  }
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String, io::Error> {
+  String::from_utf8(bytes)
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line was not valid UTF-8"))
+}
+
+impl ExtendedDecoder for LinesCodec {
+  type Item = String;
+  type Error = io::Error;
+
+  fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<String>, io::Error> {
+    match buffer.iter().position(|&byte| byte == b'\n') {
+      Some(newline) => {
+        let mut line = buffer.drain(..newline + 1).collect::<Vec<u8>>();
+        line.pop();
+        if line.last() == Some(&b'\r') {
+          line.pop();
+        }
+        utf8(line).map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  fn decode_eof(&mut self, buffer: &mut Vec<u8>) -> Result<Option<String>, io::Error> {
+    match self.decode(buffer)? {
+      Some(line) => Ok(Some(line)),
+      None if buffer.is_empty() => Ok(None),
+      None => utf8(buffer.split_off(0)).map(Some),
+    }
+  }
+}
+
+impl ExtendedEncoder for LinesCodec {
+  type Item = String;
+  type Error = io::Error;
+
+  fn encode(&mut self, line: String, buffer: &mut Vec<u8>) -> Result<(), io::Error> {
+    if line.contains('\n') {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "line contains a newline"));
+    }
+    buffer.extend_from_slice(line.as_bytes());
+    buffer.push(b'\n');
+    Ok(())
+  }
+}
+
+/// A codec for binary frames, each preceded by its length as a big-endian
+/// `u32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthDelimitedCodec {
+  max_frame_length: usize,
+}
+
+impl LengthDelimitedCodec {
+  pub fn new() -> LengthDelimitedCodec {
+    LengthDelimitedCodec{max_frame_length: 8 * 1024 * 1024}
+  }
+
+  /// Set the length of the longest frame which will be encoded or decoded
+  /// before failing with `InvalidData`
+  pub fn with_max_frame_length(max_frame_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec{max_frame_length}
+  }
+
+  fn check_length(&self, length: usize) -> Result<(), io::Error> {
+    if length > self.max_frame_length {
+      Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+        "frame length {} exceeds maximum of {}", length, self.max_frame_length
+      )))
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl Default for LengthDelimitedCodec {
+  fn default() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::new()
+  }
+}
+
+impl ExtendedDecoder for LengthDelimitedCodec {
+  type Item = Vec<u8>;
+  type Error = io::Error;
+
+  fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, io::Error> {
+    if buffer.len() < 4 {
+      return Ok(None);
+    }
+
+    let length = buffer[..4].iter().fold(0usize, |length, &byte| length << 8 | byte as usize);
+    self.check_length(length)?;
+
+    if buffer.len() < 4 + length {
+      return Ok(None);
+    }
+
+    let frame = buffer.drain(..4 + length).skip(4).collect();
+    Ok(Some(frame))
+  }
+}
+
+impl ExtendedEncoder for LengthDelimitedCodec {
+  type Item = Vec<u8>;
+  type Error = io::Error;
+
+  fn encode(&mut self, frame: Vec<u8>, buffer: &mut Vec<u8>) -> Result<(), io::Error> {
+    self.check_length(frame.len())?;
+    let length = frame.len() as u32;
+    buffer.extend_from_slice(&[(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]);
+    buffer.extend_from_slice(&frame);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::pipe::duplex;
+  use futures::{future, stream};
+
+  #[test]
+  fn lines_round_trip() {
+    let (a, b) = duplex(16);
+    let lines = vec!["hello".to_string(), "".to_string(), "a somewhat longer line".to_string()];
+
+    let sender = Framed::new(a, LinesCodec::new())
+      .send_all(stream::iter_ok::<_, io::Error>(lines.clone()))
+      // drop the sender to close the pipe
+      .map(|_| ());
+    let receiver = Framed::new(b, LinesCodec::new()).collect();
+
+    let (_, received) = sender.join(receiver).wait().unwrap();
+    assert_eq!(received, lines);
+  }
+
+  #[test]
+  fn lines_strip_carriage_returns_and_keep_final_line() {
+    let mut codec = LinesCodec::new();
+    let mut buffer = b"one\r\ntwo".to_vec();
+    assert_eq!(codec.decode(&mut buffer).unwrap(), Some("one".to_string()));
+    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    assert_eq!(codec.decode_eof(&mut buffer).unwrap(), Some("two".to_string()));
+    assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+  }
+
+  #[test]
+  fn length_delimited_round_trip() {
+    let (a, b) = duplex(7);
+    let frames = vec![vec![], vec![1, 2, 3], (0..255).collect::<Vec<u8>>(), vec![0; 10000]];
+
+    let sender = Framed::new(a, LengthDelimitedCodec::new())
+      .send_all(stream::iter_ok::<_, io::Error>(frames.clone()))
+      .map(|_| ());
+    let receiver = Framed::new(b, LengthDelimitedCodec::new()).collect();
+
+    let mut core = Core::new().unwrap();
+    let (_, received) = core.run(sender.join(receiver)).unwrap();
+    assert_eq!(received, frames);
+  }
+
+  #[test]
+  fn length_delimited_rejects_long_frames() {
+    let mut codec = LengthDelimitedCodec::with_max_frame_length(4);
+    assert!(codec.encode(vec![0; 5], &mut Vec::new()).is_err());
+    let mut buffer = vec![0, 0, 0, 5, 0, 0, 0, 0, 0];
+    assert_eq!(codec.decode(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn truncated_frame_at_end_of_input_is_an_error() {
+    let (a, b) = duplex(16);
+    let sender = future::lazy(move || {
+      let mut a = a;
+      write_adapter(&mut a, &[0, 0, 0, 4, 1, 2]).map(|_| ())
+    });
+    let receiver = Framed::new(b, LengthDelimitedCodec::new()).collect();
+    let result = sender.and_then(|()| receiver).wait();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn write_buffer_applies_backpressure() {
+    let (a, _b) = duplex(16);
+    let mut framed = Framed::new(a, LengthDelimitedCodec::new()).with_high_water_mark(64);
+
+    future::lazy(|| {
+      let mut accepted = 0;
+      while let AsyncSink::Ready = framed.start_send(vec![0; 12]).unwrap() {
+        accepted += 1;
+        assert!(accepted < 100, "write buffer is unbounded");
+      }
+      // 16 bytes are buffered by the pipe, and up to the high water mark,
+      // plus one frame, by the framed write buffer
+      assert!(framed.write_buffer.len() <= 64 + 16);
+      assert!(framed.poll_complete().unwrap().is_not_ready());
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
@@ -0,0 +1,423 @@
+use common::*;
+use extended::common::*;
+use extended::oneshot;
+use extended::sleeper::Sleeper;
+
+use std::mem;
+
+/// Incoming messages which may be responses to requests
+pub trait Correlated {
+  /// The id of the request that this message answers, if any
+  fn correlation_id(&self) -> Option<u64>;
+}
+
+/// Errors produced by a `Correlator`, with `SE` and `KE` being the errors of
+/// the underlying stream and sink
+#[derive(Debug)]
+pub enum Error<SE, KE> {
+  /// The underlying stream failed
+  Stream(SE),
+  /// The underlying sink failed
+  Sink(KE),
+}
+
+impl<SE: fmt::Display, KE: fmt::Display> fmt::Display for Error<SE, KE> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      Error::Stream(ref error) => write!(f, "underlying stream failed: {}", error),
+      Error::Sink(ref error) => write!(f, "underlying sink failed: {}", error),
+    }
+  }
+}
+
+/// The error produced by a `Response` that will never resolve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+  /// The connection closed or failed before a response arrived
+  Closed,
+  /// No response arrived before the timeout
+  TimedOut,
+}
+
+impl fmt::Display for RequestError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      RequestError::Closed => write!(f, "connection closed before response arrived"),
+      RequestError::TimedOut => write!(f, "timed out waiting for response"),
+    }
+  }
+}
+
+struct Shared<I, O> {
+  next_id:   u64,
+  pending:   HashMap<u64, oneshot::Sender<I>>,
+  /// requests which timed out or whose response was dropped, whose
+  /// responses are discarded if they arrive later
+  abandoned: HashSet<u64>,
+  outgoing:  VecDeque<O>,
+  closed:    bool,
+  /// the task polling the correlator, which must be woken to send requests
+  task:      Option<Task>,
+}
+
+impl<I, O> Shared<I, O> {
+  /// Fail all pending and future requests
+  fn close(&mut self) {
+    self.closed = true;
+    self.pending.clear();
+    self.abandoned.clear();
+    self.outgoing.clear();
+  }
+}
+
+/// A stream of the messages from an underlying stream which aren't
+/// responses to requests made with its `Requester`
+///
+/// The correlator must be polled for requests to be sent and responses to
+/// be delivered. When the underlying stream ends or fails, or the
+/// correlator is dropped, all pending requests fail.
+pub struct Correlator<S: ExtendedStream, K: ExtendedSink> {
+  stream: S,
+  sink:   K,
+  shared: Arc<Mutex<Shared<S::Item, K::SinkItem>>>,
+}
+
+/// A handle for making requests through a `Correlator`, which can be cloned
+/// and sent to other tasks
+pub struct Requester<I, O> {
+  shared: Arc<Mutex<Shared<I, O>>>,
+}
+
+impl<S, K> Correlator<S, K>
+  where S: ExtendedStream,
+        S::Item: Correlated,
+        K: ExtendedSink,
+{
+  pub fn new(stream: S, sink: K) -> (Correlator<S, K>, Requester<S::Item, K::SinkItem>) {
+    let shared = Arc::new(Mutex::new(Shared {
+      next_id:   0,
+      pending:   HashMap::new(),
+      abandoned: HashSet::new(),
+      outgoing:  VecDeque::new(),
+      closed:    false,
+      task:      None,
+    }));
+
+    (Correlator{stream, sink, shared: shared.clone()}, Requester{shared})
+  }
+
+  /// Send as many queued requests as the underlying sink will take
+  fn send_requests(&mut self, task_handle: &mut TaskHandle) -> Result<(), Error<S::Error, K::SinkError>> {
+    // take the queued requests, so that requesters aren't blocked while we
+    // wait on the sink
+    let mut requests = {
+      let mut shared = self.shared.lock().unwrap();
+      let (task, _agreement_to_notify) = task_handle.i_will_notify();
+      shared.task = Some(task);
+      mem::take(&mut shared.outgoing)
+    };
+
+    while let Some(request) = requests.pop_front() {
+      if let ExtendedAsyncSink::NotReady(request, _agreement_to_notify)
+        = self.sink.extended_start_send(task_handle, request).map_err(Error::Sink)?
+      {
+        requests.push_front(request);
+        break;
+      }
+    }
+
+    // put back any unsent requests, ahead of those queued in the meantime
+    if !requests.is_empty() {
+      let mut shared = self.shared.lock().unwrap();
+      requests.append(&mut shared.outgoing);
+      shared.outgoing = requests;
+    }
+
+    self.sink.extended_poll_complete(task_handle).map_err(Error::Sink)?;
+    Ok(())
+  }
+}
+
+impl<S: ExtendedStream, K: ExtendedSink> Drop for Correlator<S, K> {
+  fn drop(&mut self) {
+    self.shared.lock().unwrap().close();
+  }
+}
+
+impl<S, K> ExtendedStream for Correlator<S, K>
+  where S: ExtendedStream,
+        S::Item: Correlated,
+        K: ExtendedSink,
+{
+  type Item = S::Item;
+  type Error = Error<S::Error, K::SinkError>;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    let result = self.send_requests(task_handle);
+    if result.is_err() {
+      self.shared.lock().unwrap().close();
+    }
+    result?;
+
+    loop {
+      let message = match self.stream.extended_poll(task_handle) {
+        Ok(ExtendedAsync::Ready(Some(message))) => message,
+        Ok(ExtendedAsync::Ready(None)) => {
+          self.shared.lock().unwrap().close();
+          return Ok(ExtendedAsync::Ready(None));
+        }
+        Ok(ExtendedAsync::NotReady(agreement_to_notify)) => return Ok(ExtendedAsync::NotReady(agreement_to_notify)),
+        Err(err) => {
+          self.shared.lock().unwrap().close();
+          return Err(Error::Stream(err));
+        }
+      };
+
+      let (sender, abandoned) = match message.correlation_id() {
+        Some(id) => {
+          let mut shared = self.shared.lock().unwrap();
+          (shared.pending.remove(&id), shared.abandoned.remove(&id))
+        }
+        None => (None, false),
+      };
+
+      match sender {
+        Some(sender) => { let _ = sender.send(message); }
+        // the request timed out or its response was dropped, so nobody is
+        // waiting for this any more
+        None if abandoned => {}
+        None => return Ok(ExtendedAsync::Ready(Some(message))),
+      }
+    }
+  }
+}
+
+impl<S, K> Stream for Correlator<S, K>
+  where S: ExtendedStream,
+        S::Item: Correlated,
+        K: ExtendedSink,
+{
+  type Item = S::Item;
+  type Error = Error<S::Error, K::SinkError>;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<I, O> Requester<I, O> {
+  /// Send a request, built by `build` from a newly assigned id, returning a
+  /// future which resolves to the response with that id
+  pub fn request<F: FnOnce(u64) -> O>(&self, build: F) -> Response<I, O> {
+    let (sender, receiver) = oneshot::channel();
+
+    let (id, task) = {
+      let mut shared = self.shared.lock().unwrap();
+      if shared.closed {
+        // dropping the sender fails the response immediately
+        return Response{receiver, deadline: None, id: None, shared: self.shared.clone()};
+      }
+      let id = shared.next_id;
+      shared.next_id += 1;
+      shared.pending.insert(id, sender);
+      shared.outgoing.push_back(build(id));
+      (id, shared.task.take())
+    };
+
+    if let Some(task) = task {
+      task.notify();
+    }
+
+    Response{receiver, deadline: None, id: Some(id), shared: self.shared.clone()}
+  }
+
+  /// Like `request`, but fail with `RequestError::TimedOut` if no response
+  /// arrives within `timeout`
+  pub fn request_with_timeout<F: FnOnce(u64) -> O>(&self, build: F, timeout: Duration) -> Response<I, O> {
+    let mut response = self.request(build);
+    response.deadline = Some(Sleeper::new(timeout));
+    response
+  }
+
+  /// The number of requests still waiting for a response
+  pub fn pending(&self) -> usize {
+    self.shared.lock().unwrap().pending.len()
+  }
+}
+
+impl<I, O> Clone for Requester<I, O> {
+  fn clone(&self) -> Requester<I, O> {
+    Requester{shared: self.shared.clone()}
+  }
+}
+
+/// A future which resolves to the response to a request
+///
+/// A request which times out or whose response is dropped stops waiting, and
+/// its response is discarded if it arrives later.
+pub struct Response<I, O> {
+  receiver: oneshot::Receiver<I>,
+  deadline: Option<Sleeper>,
+  /// the id of the request, unless the correlator had already closed
+  id:       Option<u64>,
+  shared:   Arc<Mutex<Shared<I, O>>>,
+}
+
+impl<I, O> Response<I, O> {
+  /// Stop waiting for the response
+  fn abandon(&mut self) {
+    if let Some(id) = self.id.take() {
+      let mut shared = self.shared.lock().unwrap();
+      if shared.pending.remove(&id).is_some() {
+        shared.abandoned.insert(id);
+      }
+    }
+  }
+}
+
+impl<I, O> Drop for Response<I, O> {
+  fn drop(&mut self) {
+    self.abandon();
+  }
+}
+
+impl<I, O> ExtendedFuture for Response<I, O> {
+  type Item = I;
+  type Error = RequestError;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let agreement_to_notify = match self.receiver.extended_poll(task_handle) {
+      Ok(ExtendedAsync::Ready(response)) => return Ok(ExtendedAsync::Ready(response)),
+      Ok(ExtendedAsync::NotReady(agreement_to_notify)) => agreement_to_notify,
+      Err(oneshot::Canceled) => return Err(RequestError::Closed),
+    };
+
+    if let Some(ref mut deadline) = self.deadline {
+      if let Ok(ExtendedAsync::Ready(())) = deadline.extended_poll(task_handle) {
+        self.abandon();
+        return Err(RequestError::TimedOut);
+      }
+    }
+
+    Ok(ExtendedAsync::NotReady(agreement_to_notify))
+  }
+}
+
+impl<I, O> Future for Response<I, O> {
+  type Item = I;
+  type Error = RequestError;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::mpsc::{self, Receiver, Sender};
+  use futures::stream;
+
+  #[derive(Debug, Clone, PartialEq)]
+  struct Message {
+    id:   Option<u64>,
+    body: String,
+  }
+
+  impl Correlated for Message {
+    fn correlation_id(&self) -> Option<u64> {
+      self.id
+    }
+  }
+
+  fn message(id: Option<u64>, body: &str) -> Message {
+    Message{id, body: body.to_string()}
+  }
+
+  type Client = (Correlator<Receiver<Message>, Sender<Message>>, Requester<Message, Message>);
+
+  /// A correlator, along with the server's ends of its underlying channels
+  fn connect() -> (Client, Sender<Message>, Receiver<Message>) {
+    let (to_client, from_server) = mpsc::unbounded();
+    let (to_server, from_client) = mpsc::unbounded();
+    (Correlator::new(from_server, to_server), to_client, from_client)
+  }
+
+  #[test]
+  fn responses_are_correlated_by_id() {
+    let ((correlator, requester), to_client, from_client) = connect();
+
+    let server = thread::spawn(move || {
+      let requests = from_client.take(2).collect().wait().unwrap();
+      let responses = vec![
+        message(requests[1].id, "second response"),
+        message(None, "news"),
+        message(requests[0].id, "first response"),
+        message(Some(1000), "unknown id"),
+      ];
+      let _ = to_client.send_all(stream::iter_ok(responses)).wait().unwrap();
+    });
+
+    let first = requester.request(|id| message(Some(id), "first"));
+    let second = requester.request(|id| message(Some(id), "second"));
+    let (uncorrelated, (first, second)) = correlator.collect().join(first.map_err(|_| unreachable!()).join(second.map_err(|_| unreachable!()))).wait().unwrap();
+    server.join().unwrap();
+
+    assert_eq!(first.body, "first response");
+    assert_eq!(second.body, "second response");
+    assert_eq!(uncorrelated, vec![message(None, "news"), message(Some(1000), "unknown id")]);
+    assert_eq!(requester.pending(), 0);
+  }
+
+  #[test]
+  fn pending_requests_fail_on_close() {
+    let ((correlator, requester), to_client, _from_client) = connect();
+    let response = requester.request(|id| message(Some(id), "hello"));
+    drop(to_client);
+
+    assert_eq!(correlator.collect().wait().unwrap(), vec![]);
+    assert_eq!(response.wait().unwrap_err(), RequestError::Closed);
+    assert_eq!(requester.request(|id| message(Some(id), "too late")).wait().unwrap_err(), RequestError::Closed);
+  }
+
+  #[test]
+  fn requests_time_out() {
+    let mut core = Core::new().unwrap();
+    let ((correlator, requester), _to_client, _from_client) = connect();
+    core.handle().spawn(correlator.for_each(|_| Ok(())).map_err(|err| panic!("correlator failed: {}", err)));
+
+    let start = Instant::now();
+    let response = requester.request_with_timeout(|id| message(Some(id), "hello"), Duration::from_millis(50));
+    assert_eq!(core.run(response).unwrap_err(), RequestError::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(requester.pending(), 0);
+
+    drop(requester.request(|id| message(Some(id), "goodbye")));
+    assert_eq!(requester.pending(), 0);
+  }
+
+  #[test]
+  fn only_abandoned_responses_are_discarded() {
+    let ((correlator, requester), to_client, from_client) = connect();
+    let answered = requester.request(|id| message(Some(id), "answered"));
+    drop(requester.request(|id| message(Some(id), "abandoned")));
+
+    let server = thread::spawn(move || {
+      let requests = from_client.take(2).collect().wait().unwrap();
+      let responses = vec![
+        message(requests[0].id, "response"),
+        message(requests[1].id, "late response"),
+        message(requests[0].id, "duplicate response"),
+      ];
+      let _ = to_client.send_all(stream::iter_ok(responses)).wait().unwrap();
+    });
+
+    let (uncorrelated, answered) = correlator.collect().join(answered.map_err(|_| unreachable!())).wait().unwrap();
+    server.join().unwrap();
+
+    assert_eq!(answered.body, "response");
+    assert_eq!(uncorrelated, vec![message(Some(0), "duplicate response")]);
+  }
+}
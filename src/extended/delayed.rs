@@ -1,23 +1,44 @@
 use common::*;
 use extended::common::*;
 
-/// A Future that produces a random `u8` after a delay of 1 second
-pub struct Producer {
-  inner:   extended::instant::Producer,
+/// A Future that produces the next value from an iterator, by default a
+/// random `u8`, after a delay of 1 second
+pub struct Producer<I = Random> {
+  inner:   extended::instant::Producer<I>,
   sleeper: extended::sleeper::Sleeper,
 }
 
 impl Producer {
   pub fn new() -> Producer {
+    Producer::from_items(Random::new())
+  }
+
+  /// A producer of a random `u8` drawn from a generator seeded with `seed`
+  pub fn with_seed(seed: u64) -> Producer {
+    Producer::from_items(Random::with_seed(seed))
+  }
+}
+
+impl<T, F: FnOnce() -> T> Producer<iter::OnceWith<F>> {
+  /// A producer which returns the value made by `generate`
+  pub fn from_fn(generate: F) -> Producer<iter::OnceWith<F>> {
+    Producer::from_items(iter::once_with(generate))
+  }
+}
+
+impl<I: Iterator> Producer<I> {
+  /// A producer which returns the next value from `items`, which must not be
+  /// empty
+  pub fn from_items(items: I) -> Producer<I> {
     Producer {
-      inner:   extended::instant::Producer::new(),
+      inner:   extended::instant::Producer::from_items(items),
       sleeper: extended::sleeper::Sleeper::new(Duration::new(1, 0)),
     }
   }
 }
 
-impl ExtendedFuture for Producer {
-  type Item = u8;
+impl<I: Iterator> ExtendedFuture for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
 
   fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
@@ -26,8 +47,8 @@ impl ExtendedFuture for Producer {
   }
 }
 
-impl Future for Producer {
-  type Item = u8;
+impl<I: Iterator> Future for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
 
   fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -35,7 +56,7 @@ impl Future for Producer {
   }
 }
 
-impl fmt::Debug for Producer {
+impl<I: fmt::Debug> fmt::Debug for Producer<I> {
   fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
     f.debug_struct("Producer")
       .field("inner", &self.inner)
@@ -44,22 +65,30 @@ impl fmt::Debug for Producer {
   }
 }
 
-/// A Future that consumes a `u8` after a delay of 1 second
-pub struct Consumer {
-  inner:   extended::instant::Consumer,
+/// A Future that consumes a value after a delay of 1 second, by passing it
+/// to a callback which by default discards it
+pub struct Consumer<T = u8, F = fn(T)> {
+  inner:   extended::instant::Consumer<T, F>,
   sleeper: extended::sleeper::Sleeper,
 }
 
 impl Consumer {
   pub fn new(value: u8) -> Consumer {
+    Consumer::with_callback(value, drop)
+  }
+}
+
+impl<T, F: FnOnce(T)> Consumer<T, F> {
+  /// A consumer which passes `value` to `callback`
+  pub fn with_callback(value: T, callback: F) -> Consumer<T, F> {
     Consumer {
-      inner:   extended::instant::Consumer::new(value),
+      inner:   extended::instant::Consumer::with_callback(value, callback),
       sleeper: extended::sleeper::Sleeper::new(Duration::new(1, 0)),
     }
   }
 }
 
-impl ExtendedFuture for Consumer {
+impl<T, F: FnOnce(T)> ExtendedFuture for Consumer<T, F> {
   type Item = ();
   type Error = Void;
 
@@ -69,7 +98,7 @@ impl ExtendedFuture for Consumer {
   }
 }
 
-impl Future for Consumer {
+impl<T, F: FnOnce(T)> Future for Consumer<T, F> {
   type Item = ();
   type Error = Void;
 
@@ -81,6 +110,7 @@ impl Future for Consumer {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::rc::Rc;
 
   #[test]
   fn producer_completes_in_about_one_second() {
@@ -97,9 +127,9 @@ mod tests {
   fn producer_returns_all_values() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let seen = Arc::new(RefCell::new(HashSet::new()));
+    let seen = Rc::new(RefCell::new(HashSet::new()));
     let expected = 2usize.pow(8);
-    let outstanding = Arc::new(RefCell::new(0u64));
+    let outstanding = Rc::new(RefCell::new(0u64));
 
     loop {
       if seen.borrow().len() == expected {
@@ -111,7 +141,7 @@ mod tests {
           let seen = seen.clone();
           let outstanding = outstanding.clone();
           Producer::new()
-            .map(move |value| { *outstanding.borrow_mut() -= 1; seen.borrow_mut().insert(value); () })
+            .map(move |value| { *outstanding.borrow_mut() -= 1; seen.borrow_mut().insert(value); })
             .map_err(|err| panic!("got error: {}", err))
         };
         *outstanding.borrow_mut() += 1;
@@ -132,4 +162,14 @@ mod tests {
     assert!(elapsed < Duration::new(1, 200_000_000));
     assert!(elapsed > Duration::new(0, 800_000_000));
   }
+
+  #[test]
+  fn generic() {
+    let start = Instant::now();
+    let mut consumed = None;
+    let value = Producer::from_fn(|| "hello").wait().unwrap();
+    Consumer::with_callback(value, |value| consumed = Some(value)).wait().unwrap();
+    assert_eq!(consumed, Some("hello"));
+    assert!(start.elapsed() > Duration::new(1, 800_000_000));
+  }
 }
@@ -1,51 +1,128 @@
 use common::*;
+use limit::{Limit, Shutdown};
 use extended::common::*;
 
-/// A Stream that produces a random `u8` every second
-#[derive(Debug)]
-pub struct Producer {
-  next: extended::delayed::Producer,
+/// A Stream that produces values from an iterator, one every second, by
+/// default an endless series of random `u8`s
+pub struct Producer<I = Random> {
+  items:   I,
+  sleeper: extended::sleeper::Sleeper,
+  limit:   Limit,
 }
 
 impl Producer {
   pub fn new() -> Producer {
-    Producer{next: extended::delayed::Producer::new()}
+    Producer::from_items(Random::new())
+  }
+
+  /// A producer of random `u8`s drawn from a generator seeded with `seed`,
+  /// which are the same on every run
+  pub fn with_seed(seed: u64) -> Producer {
+    Producer::from_items(Random::with_seed(seed))
+  }
+}
+
+impl<T, F: FnMut() -> T> Producer<iter::RepeatWith<F>> {
+  /// An endless producer of values made by `generate`
+  pub fn from_fn(generate: F) -> Producer<iter::RepeatWith<F>> {
+    Producer::from_items(iter::repeat_with(generate))
   }
 }
 
-impl ExtendedStream for Producer {
-  type Item = u8;
+impl<I: Iterator> Producer<I> {
+  /// A producer of `items`, which ends a second after they run out
+  pub fn from_items<J: IntoIterator<IntoIter=I>>(items: J) -> Producer<I> {
+    Producer {
+      items:   items.into_iter(),
+      sleeper: extended::sleeper::Sleeper::new(Duration::new(1, 0)),
+      limit:   Limit::none(),
+    }
+  }
+
+  /// End the stream after `n` more items
+  pub fn end_after(mut self, n: usize) -> Producer<I> {
+    self.limit.items(n);
+    self
+  }
+
+  /// End the stream at `deadline`
+  pub fn end_at(mut self, deadline: Instant) -> Producer<I> {
+    self.limit.deadline(deadline);
+    self.sleeper = extended::sleeper::Sleeper::new(self.limit.wait(Duration::new(1, 0)));
+    self
+  }
+
+  /// End the stream when `shutdown` fires
+  pub fn end_on(mut self, shutdown: &Shutdown) -> Producer<I> {
+    self.limit.shutdown(shutdown);
+    self
+  }
+}
+
+impl<I: Iterator> ExtendedStream for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
   fn extended_poll(&mut self, task_handle: &mut TaskHandle)
     -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
   {
-    let next = extended_try_ready!(self.next.extended_poll(task_handle));
-    self.next = extended::delayed::Producer::new();
-    Ok(ExtendedAsync::Ready(Some(next)))
+    if self.limit.reached() {
+      return Ok(ExtendedAsync::Ready(None));
+    }
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.sleeper.extended_poll(task_handle)? {
+      let (task, _agreement_to_notify) = task_handle.i_will_notify();
+      self.limit.wake_on_shutdown(task);
+      return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+    }
+    // the sleeper is cut short by the deadline
+    if self.limit.reached() {
+      return Ok(ExtendedAsync::Ready(None));
+    }
+    self.sleeper = extended::sleeper::Sleeper::new(self.limit.wait(Duration::new(1, 0)));
+    self.limit.produced();
+    Ok(ExtendedAsync::Ready(self.items.next()))
   }
 }
 
-impl Stream for Producer {
-  type Item = u8;
+impl<I: Iterator> Stream for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
   fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
     stream_adapter(self)
   }
 }
 
-/// A Stream that consumes a `u8` every second
-pub struct Consumer {
-  sending: Option<extended::delayed::Consumer>,
+impl<I: fmt::Debug> fmt::Debug for Producer<I> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    f.debug_struct("Producer")
+      .field("items", &self.items)
+      .field("limit", &self.limit)
+      .field("timeout", &"...")
+      .finish()
+  }
+}
+
+/// A Sink that consumes a value every second, by passing it to a callback
+/// which by default discards it
+pub struct Consumer<T = u8, F = fn(T)> {
+  sending:  Option<(T, extended::sleeper::Sleeper)>,
+  callback: F,
 }
 
 impl Consumer {
   pub fn new() -> Consumer {
-    Consumer{sending: None}
+    Consumer::with_callback(drop)
   }
 }
 
-impl ExtendedSink for Consumer {
-  type SinkItem = u8;
+impl<T, F: FnMut(T)> Consumer<T, F> {
+  /// A consumer which passes each value to `callback`
+  pub fn with_callback(callback: F) -> Consumer<T, F> {
+    Consumer{sending: None, callback}
+  }
+}
+
+impl<T, F: FnMut(T)> ExtendedSink for Consumer<T, F> {
+  type SinkItem = T;
   type SinkError = Void;
 
   fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
@@ -56,7 +133,7 @@ impl ExtendedSink for Consumer {
     }
 
     assert!(self.sending.is_none());
-    self.sending = Some(extended::delayed::Consumer::new(item));
+    self.sending = Some((item, extended::sleeper::Sleeper::new(Duration::new(1, 0))));
 
     Ok(ExtendedAsyncSink::Ready)
   }
@@ -64,16 +141,18 @@ impl ExtendedSink for Consumer {
   fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
     -> Result<ExtendedAsync<()>, Self::SinkError>
   {
-    if self.sending.is_some() {
-      extended_try_ready!(self.sending.as_mut().unwrap().extended_poll(task_handle));
-      self.sending = None;
+    if let Some((_, ref mut sleeper)) = self.sending {
+      extended_try_ready!(sleeper.extended_poll(task_handle));
+    }
+    if let Some((item, _)) = self.sending.take() {
+      (self.callback)(item);
     }
     Ok(ExtendedAsync::Ready(()))
   }
 }
 
-impl Sink for Consumer {
-  type SinkItem = u8;
+impl<T, F: FnMut(T)> Sink for Consumer<T, F> {
+  type SinkItem = T;
   type SinkError = Void;
 
   fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
@@ -88,46 +167,86 @@ impl Sink for Consumer {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use recording::Recording;
 
   #[test]
   fn values_take_one_second_to_produce() {
     let mut core = Core::new().unwrap();
-    let start = Instant::now();
-    let producer = Producer::new().take(5);
-    let consumer = extended::instant_series::Consumer::new();
-    core.run(producer.forward(consumer)).unwrap();
-
-    let elapsed = start.elapsed();
-    assert!(elapsed < Duration::new(5, 500_000_000));
-    assert!(elapsed > Duration::new(4, 500_000_000));
+    let recorder = extended::recorder::Recorder::new();
+    let recording = recorder.recording();
+    let producer = Producer::from_items(0..5);
+    let _ = core.run(producer.forward(recorder)).unwrap();
+
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
   }
 
   #[test]
   fn values_take_one_second_to_consume() {
     let mut core = Core::new().unwrap();
-    let start = Instant::now();
-    let producer = extended::instant_series::Producer::new().take(5);
-    let consumer = Consumer::new();
+    let recording = Recording::new();
+    let producer = extended::instant_series::Producer::from_items(0..5);
+    let consumer = Consumer::with_callback(recording.callback());
 
-    core.run(producer.forward(consumer)).unwrap();
+    let _ = core.run(producer.forward(consumer)).unwrap();
 
-    let elapsed = start.elapsed();
-    assert!(elapsed < Duration::new(5, 500_000_000));
-    assert!(elapsed > Duration::new(4, 500_000_000));
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
   }
 
-
   #[test]
   fn production_and_consumption_are_concurrent() {
     let mut core = Core::new().unwrap();
-    let start = Instant::now();
-    let producer = Producer::new().take(5);
-    let consumer = Consumer::new();
+    let recording = Recording::new();
+    let producer = Producer::from_items(0..5);
+    let consumer = Consumer::with_callback(recording.callback());
+
+    let _ = core.run(producer.forward(consumer)).unwrap();
+
+    // one item a second, rather than one every two seconds
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
+  }
 
-    core.run(producer.forward(consumer)).unwrap();
+  #[test]
+  fn generic() {
+    let mut core = Core::new().unwrap();
+    let recording = Recording::new();
+    let producer = Producer::from_items(vec!["a", "b"]);
+    let consumer = Consumer::with_callback(recording.callback());
+    let _ = core.run(producer.forward(consumer)).unwrap();
+
+    recording.assert_received_in_order(&["a", "b"]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
+  }
 
+  #[test]
+  fn deadline_cuts_wait_short() {
+    let start = Instant::now();
+    let items = Producer::new().end_at(start + Duration::new(1, 500_000_000)).collect().wait().unwrap();
+    assert_eq!(items.len(), 1);
     let elapsed = start.elapsed();
-    assert!(elapsed < Duration::new(6, 500_000_000));
-    assert!(elapsed > Duration::new(5, 500_000_000));
+    assert!(elapsed < Duration::new(1, 800_000_000));
+    assert!(elapsed > Duration::new(1, 300_000_000));
+  }
+
+  #[test]
+  fn shutdown_wakes_waiting_producer() {
+    let shutdown = Shutdown::new();
+    let signal = shutdown.clone();
+    let thread = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(200));
+      signal.fire();
+    });
+
+    let start = Instant::now();
+    let items = Producer::new().end_on(&shutdown).collect().wait().unwrap();
+    thread.join().unwrap();
+    assert!(items.is_empty());
+    assert!(start.elapsed() < Duration::from_millis(800));
   }
 }
@@ -0,0 +1,304 @@
+use common::*;
+use extended::common::*;
+
+/// The error produced by a `Faulty` wrapper, either injected or passed
+/// through from the wrapped object
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultError<E> {
+  /// A fault was injected on the `poll`th counted operation, starting at one
+  Injected{poll: usize},
+  /// The wrapped object failed
+  Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for FaultError<E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      FaultError::Injected{poll} => write!(f, "fault injected on poll {}", poll),
+      FaultError::Inner(ref error) => write!(f, "{}", error),
+    }
+  }
+}
+
+/// Which sink operation faults are injected into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkPhase {
+  StartSend,
+  PollComplete,
+}
+
+/// When a `Faulty` wrapper should fail or end early
+#[derive(Debug, Clone)]
+pub struct Faults {
+  fail_on:     Option<usize>,
+  probability: f64,
+  end_after:   Option<usize>,
+  phase:       SinkPhase,
+  rng:         Random,
+}
+
+impl Faults {
+  /// Never inject a fault
+  pub fn none() -> Faults {
+    Faults {
+      fail_on:     None,
+      probability: 0.0,
+      end_after:   None,
+      phase:       SinkPhase::StartSend,
+      rng:         Random::new(),
+    }
+  }
+
+  /// Fail on the `n`th counted operation, starting at one
+  pub fn fail_on_poll(self, n: usize) -> Faults {
+    assert!(n > 0, "polls are counted from one");
+    Faults{fail_on: Some(n), ..self}
+  }
+
+  /// Fail each counted operation with probability `probability`
+  pub fn fail_with_probability(self, probability: f64) -> Faults {
+    assert!((0.0..=1.0).contains(&probability), "probability must be between zero and one");
+    Faults{probability, ..self}
+  }
+
+  /// Draw random failures from a generator seeded with `seed`
+  pub fn with_seed(self, seed: u64) -> Faults {
+    Faults{rng: Random::with_seed(seed), ..self}
+  }
+
+  /// End a wrapped stream after it has yielded `n` items
+  pub fn end_after(self, n: usize) -> Faults {
+    Faults{end_after: Some(n), ..self}
+  }
+
+  /// Count and fail sink operations in `phase`, by default `StartSend`
+  pub fn in_phase(self, phase: SinkPhase) -> Faults {
+    Faults{phase, ..self}
+  }
+}
+
+/// A wrapper around a Future, Stream, or Sink, which injects faults
+///
+/// For futures and streams, every poll is counted. For sinks, only
+/// operations in the configured `SinkPhase` are counted.
+pub struct Faulty<T> {
+  inner:  T,
+  faults: Faults,
+  polls:  usize,
+  items:  usize,
+}
+
+impl<T> Faulty<T> {
+  pub fn new(inner: T, faults: Faults) -> Faulty<T> {
+    Faulty{inner, faults, polls: 0, items: 0}
+  }
+
+  pub fn into_inner(self) -> T {
+    self.inner
+  }
+
+  /// Count an operation, and decide whether it should fail
+  fn check<E>(&mut self) -> Result<(), FaultError<E>> {
+    self.polls += 1;
+    let probability = self.faults.probability;
+    if self.faults.fail_on == Some(self.polls) || (probability > 0.0 && self.faults.rng.gen::<f64>() < probability) {
+      Err(FaultError::Injected{poll: self.polls})
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl<T: ExtendedFuture> ExtendedFuture for Faulty<T> {
+  type Item = T::Item;
+  type Error = FaultError<T::Error>;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    self.check()?;
+    self.inner.extended_poll(task_handle).map_err(FaultError::Inner)
+  }
+}
+
+impl<T: ExtendedFuture> Future for Faulty<T> {
+  type Item = T::Item;
+  type Error = FaultError<T::Error>;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+impl<T: ExtendedStream> ExtendedStream for Faulty<T> {
+  type Item = T::Item;
+  type Error = FaultError<T::Error>;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    if self.faults.end_after == Some(self.items) {
+      return Ok(ExtendedAsync::Ready(None));
+    }
+    self.check()?;
+    let item = extended_try_ready!(self.inner.extended_poll(task_handle).map_err(FaultError::Inner));
+    if item.is_some() {
+      self.items += 1;
+    }
+    Ok(ExtendedAsync::Ready(item))
+  }
+}
+
+impl<T: ExtendedStream> Stream for Faulty<T> {
+  type Item = T::Item;
+  type Error = FaultError<T::Error>;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<T: ExtendedSink> ExtendedSink for Faulty<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = FaultError<T::SinkError>;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if self.faults.phase == SinkPhase::StartSend {
+      self.check()?;
+    }
+    self.inner.extended_start_send(task_handle, item).map_err(FaultError::Inner)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    if self.faults.phase == SinkPhase::PollComplete {
+      self.check()?;
+    }
+    self.inner.extended_poll_complete(task_handle).map_err(FaultError::Inner)
+  }
+
+  /// Closing drives the sink to completion, so it counts as `PollComplete`
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    if self.faults.phase == SinkPhase::PollComplete {
+      self.check()?;
+    }
+    self.inner.extended_close(task_handle).map_err(FaultError::Inner)
+  }
+}
+
+impl<T: ExtendedSink> Sink for Faulty<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = FaultError<T::SinkError>;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::adapter::{self, Adapter, Echo};
+  use extended::buffered;
+  use extended::instant_series::{Consumer, Producer};
+  use extended::recorder::Recorder;
+
+  fn producer(faults: Faults) -> Faulty<Producer<ops::Range<u8>>> {
+    Faulty::new(Producer::from_items(0..10), faults)
+  }
+
+  #[test]
+  fn future_fails_on_first_poll() {
+    let future = Faulty::new(extended::instant::Producer::new(), Faults::none().fail_on_poll(1));
+    assert_eq!(future.wait(), Err(FaultError::Injected{poll: 1}));
+  }
+
+  #[test]
+  fn stream_error_ends_forward() {
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let result = producer(Faults::none().fail_on_poll(4)).forward(recorder.sink_map_err(|v| match v {})).wait();
+    assert_eq!(result.err(), Some(FaultError::Injected{poll: 4}));
+    recording.assert_received_in_order(&[0, 1, 2]);
+  }
+
+  #[test]
+  fn start_send_error_ends_forward() {
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let sink = Faulty::new(recorder, Faults::none().fail_on_poll(3));
+    let result = Producer::from_items(0..10).map_err(|v| match v {}).forward(sink).wait();
+    assert_eq!(result.err(), Some(FaultError::Injected{poll: 3}));
+    recording.assert_received_in_order(&[0, 1]);
+  }
+
+  #[test]
+  fn poll_complete_error_ends_forward() {
+    let sink = Faulty::new(Consumer::new(), Faults::none().in_phase(SinkPhase::PollComplete).fail_on_poll(1));
+    let result = Producer::from_items(0..10).map_err(|v| match v {}).forward(sink).wait();
+    assert_eq!(result.err(), Some(FaultError::Injected{poll: 1}));
+  }
+
+  #[test]
+  fn buffered_consumer_passes_on_inner_error() {
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let inner = Faulty::new(recorder, Faults::none().fail_on_poll(3));
+    let sink = buffered::Consumer::with_inner(10, inner);
+    let result = Producer::from_items(0..10).map_err(|v| match v {}).forward(sink).wait();
+    assert_eq!(result.err(), Some(FaultError::Injected{poll: 3}));
+    recording.assert_received_in_order(&[0, 1]);
+  }
+
+  #[test]
+  fn early_end() {
+    let items = producer(Faults::none().end_after(3)).collect().wait().unwrap();
+    assert_eq!(items, vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn seeded_probability_is_deterministic() {
+    let failure = |seed| producer(Faults::none().fail_with_probability(0.2).with_seed(seed)).collect().wait().err();
+    assert_eq!(failure(1), failure(1));
+    assert_eq!(producer(Faults::none().fail_with_probability(1.0)).collect().wait().err(), Some(FaultError::Injected{poll: 1}));
+    assert_eq!(producer(Faults::none().fail_with_probability(0.0)).collect().wait().unwrap().len(), 10);
+  }
+
+  #[test]
+  fn adapter_stream_error() {
+    let stream = producer(Faults::none().fail_on_poll(3));
+    let adapter = Adapter::new(stream, Consumer::new(), Echo);
+    match adapter.collect().wait() {
+      Err(adapter::Error::Stream(FaultError::Injected{poll: 3})) => {}
+      other => panic!("expected stream error, got {:?}", other.map(|_| ()).map_err(|err| err.to_string())),
+    }
+  }
+
+  #[test]
+  fn adapter_sink_error() {
+    // even bytes are echoed back to the faulty sink, which fails on the first
+    let sink = Faulty::new(Consumer::new(), Faults::none().fail_on_poll(1));
+    let adapter = Adapter::new(Producer::from_items(0..10), sink, Echo);
+    match adapter.collect().wait() {
+      Err(adapter::Error::Sink(FaultError::Injected{poll: 1})) => {}
+      other => panic!("expected sink error, got {:?}", other.map(|_| ()).map_err(|err| err.to_string())),
+    }
+  }
+
+  #[test]
+  fn adapter_stream_ends_early() {
+    let adapter = Adapter::new(producer(Faults::none().end_after(4)), Consumer::new(), Echo);
+    assert_eq!(adapter.collect().wait().unwrap(), vec![1, 3]);
+  }
+}
@@ -0,0 +1,452 @@
+use common::*;
+use extended::common::*;
+use extended::sleeper::Sleeper;
+use extended::websocket::{self, accept};
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tokio_core::net::{Incoming, TcpListener};
+use tokio_core::reactor::Handle;
+
+/// Which side of the book a level, update, or trade is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+  Bid,
+  Ask,
+}
+
+impl Side {
+  fn name(self) -> &'static str {
+    match self {
+      Side::Bid => "bid",
+      Side::Ask => "ask",
+    }
+  }
+}
+
+/// A message from the simulated exchange
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+  /// The full book for a symbol, with bids best first and asks best first
+  Snapshot{symbol: String, sequence: u64, bids: Vec<(u64, u64)>, asks: Vec<(u64, u64)>},
+  /// The size at a price level changed, with a size of zero removing it
+  Update{symbol: String, sequence: u64, side: Side, price: u64, size: u64},
+  /// A trade against the resting order on `side`
+  Trade{symbol: String, sequence: u64, side: Side, price: u64, size: u64},
+  /// Nothing has happened for a while, but the feed is still alive
+  Heartbeat,
+}
+
+impl Message {
+  /// The message as a JSON object, with its kind in the `type` field
+  pub fn to_json(&self) -> Value {
+    let levels = |levels: &[(u64, u64)]| levels.iter().map(|&(price, size)| json!([price, size])).collect::<Vec<Value>>();
+    match *self {
+      Message::Snapshot{ref symbol, sequence, ref bids, ref asks} => json!({
+        "type": "snapshot", "symbol": symbol, "sequence": sequence, "bids": levels(bids), "asks": levels(asks),
+      }),
+      Message::Update{ref symbol, sequence, side, price, size} => json!({
+        "type": "update", "symbol": symbol, "sequence": sequence, "side": side.name(), "price": price, "size": size,
+      }),
+      Message::Trade{ref symbol, sequence, side, price, size} => json!({
+        "type": "trade", "symbol": symbol, "sequence": sequence, "side": side.name(), "price": price, "size": size,
+      }),
+      Message::Heartbeat => json!({"type": "heartbeat"}),
+    }
+  }
+}
+
+/// The simulated order book for one symbol
+struct Book {
+  symbol:   String,
+  sequence: u64,
+  /// a snapshot has been sent
+  live:     bool,
+  bids:     BTreeMap<u64, u64>,
+  asks:     BTreeMap<u64, u64>,
+}
+
+impl Book {
+  fn levels(&mut self, side: Side) -> &mut BTreeMap<u64, u64> {
+    match side {
+      Side::Bid => &mut self.bids,
+      Side::Ask => &mut self.asks,
+    }
+  }
+
+  fn best(&self, side: Side) -> Option<(u64, u64)> {
+    match side {
+      Side::Bid => self.bids.iter().next_back(),
+      Side::Ask => self.asks.iter().next(),
+    }.map(|(&price, &size)| (price, size))
+  }
+}
+
+/// A simulated exchange market-data feed, which yields an endless stream of
+/// snapshots, level updates, trades and heartbeats for a set of symbols
+///
+/// Each symbol's messages carry consecutive sequence numbers, except that
+/// occasionally some are skipped to simulate lost messages. Messages arrive
+/// after random delays between the configured minimum and maximum, and a
+/// heartbeat is sent whenever nothing else has been sent for the heartbeat
+/// interval.
+pub struct Feed {
+  books:              Vec<Book>,
+  rng:                Random,
+  depth:              u64,
+  trade_probability:  f64,
+  gap_probability:    f64,
+  arrivals:           (Duration, Duration),
+  heartbeat_interval: Option<Duration>,
+  next_arrival:       Instant,
+  last_sent:          Instant,
+}
+
+impl Feed {
+  pub fn new(symbols: &[&str]) -> Feed {
+    assert!(!symbols.is_empty(), "feed needs at least one symbol");
+    let now = Instant::now();
+    Feed {
+      books: symbols.iter().map(|symbol| Book {
+        symbol:   symbol.to_string(),
+        sequence: 0,
+        live:     false,
+        bids:     BTreeMap::new(),
+        asks:     BTreeMap::new(),
+      }).collect(),
+      rng:                Random::new(),
+      depth:              5,
+      trade_probability:  0.2,
+      gap_probability:    0.0,
+      arrivals:           (Duration::from_millis(10), Duration::from_millis(100)),
+      heartbeat_interval: None,
+      next_arrival:       now,
+      last_sent:          now,
+    }
+  }
+
+  /// Generate the same traffic, apart from timing, on every run
+  pub fn with_seed(self, seed: u64) -> Feed {
+    Feed{rng: Random::with_seed(seed), ..self}
+  }
+
+  /// The number of price levels on each side of each book, at most 1000 so
+  /// that prices quoted around the mid stay positive
+  pub fn with_depth(self, depth: u64) -> Feed {
+    assert!(depth > 0, "book depth must be greater than zero");
+    assert!(depth <= 1000, "book depth must be at most 1000");
+    Feed{depth, ..self}
+  }
+
+  /// The chance that a message is a trade rather than a level update
+  pub fn with_trade_probability(self, trade_probability: f64) -> Feed {
+    assert!((0.0..=1.0).contains(&trade_probability), "probability must be between zero and one");
+    Feed{trade_probability, ..self}
+  }
+
+  /// The chance that some sequence numbers are skipped before a message
+  pub fn with_gap_probability(self, gap_probability: f64) -> Feed {
+    assert!((0.0..=1.0).contains(&gap_probability), "probability must be between zero and one");
+    Feed{gap_probability, ..self}
+  }
+
+  /// Wait between `min` and `max` before each message
+  pub fn with_arrivals(self, min: Duration, max: Duration) -> Feed {
+    assert!(min <= max, "minimum arrival delay is greater than maximum");
+    Feed{arrivals: (min, max), ..self}
+  }
+
+  /// Send a heartbeat whenever nothing has been sent for `interval`
+  pub fn with_heartbeat_interval(self, interval: Duration) -> Feed {
+    assert!(interval > Duration::new(0, 0), "heartbeat interval must be greater than zero");
+    Feed{heartbeat_interval: Some(interval), ..self}
+  }
+
+  fn arrival_delay(&mut self) -> Duration {
+    let (min, max) = self.arrivals;
+    min + (max - min).mul_f64(self.rng.gen())
+  }
+
+  /// Advance the sequence number of book `i`, possibly skipping some
+  fn sequence(&mut self, i: usize) -> u64 {
+    let skipped = if self.gap_probability > 0.0 && self.rng.gen::<f64>() < self.gap_probability {
+      1 + self.rng.gen::<u64>() % 3
+    } else {
+      0
+    };
+    let book = &mut self.books[i];
+    book.sequence += 1 + skipped;
+    book.sequence
+  }
+
+  fn snapshot(&mut self, i: usize) -> Message {
+    let mid = 10_000 + self.rng.gen::<u64>() % 1000;
+    for offset in 1..=self.depth {
+      let (bid, ask) = (1 + self.rng.gen::<u64>() % 100, 1 + self.rng.gen::<u64>() % 100);
+      self.books[i].bids.insert(mid - offset, bid);
+      self.books[i].asks.insert(mid + offset, ask);
+    }
+    let sequence = self.sequence(i);
+    let book = &mut self.books[i];
+    book.live = true;
+    Message::Snapshot {
+      symbol: book.symbol.clone(),
+      sequence,
+      bids: book.bids.iter().rev().map(|(&price, &size)| (price, size)).collect(),
+      asks: book.asks.iter().map(|(&price, &size)| (price, size)).collect(),
+    }
+  }
+
+  fn side(&mut self) -> Side {
+    if self.rng.gen() { Side::Bid } else { Side::Ask }
+  }
+
+  fn trade(&mut self, i: usize, side: Side) -> Option<Message> {
+    let (price, resting) = self.books[i].best(side)?;
+    let size = 1 + self.rng.gen::<u64>() % resting;
+    let sequence = self.sequence(i);
+    let book = &mut self.books[i];
+    if size == resting {
+      book.levels(side).remove(&price);
+    } else {
+      book.levels(side).insert(price, resting - size);
+    }
+    Some(Message::Trade{symbol: book.symbol.clone(), sequence, side, price, size})
+  }
+
+  fn update(&mut self, i: usize, side: Side) -> Message {
+    // keep the book from crossing by quoting away from the opposite side
+    let opposite = match side { Side::Bid => Side::Ask, Side::Ask => Side::Bid };
+    let anchor = match (self.books[i].best(opposite), self.books[i].best(side)) {
+      (Some((price, _)), _) => price,
+      (None, Some((price, _))) => match side { Side::Bid => price + 1, Side::Ask => price - 1 },
+      (None, None) => 10_000,
+    };
+    let offset = 1 + self.rng.gen::<u64>() % self.depth;
+    let price = match side { Side::Bid => anchor - offset, Side::Ask => anchor + offset };
+    let size = if self.rng.gen::<u8>() < 26 { 0 } else { 1 + self.rng.gen::<u64>() % 100 };
+    let sequence = self.sequence(i);
+    let book = &mut self.books[i];
+    if size == 0 {
+      book.levels(side).remove(&price);
+    } else {
+      book.levels(side).insert(price, size);
+    }
+    Message::Update{symbol: book.symbol.clone(), sequence, side, price, size}
+  }
+
+  /// Generate the next message for a randomly chosen symbol
+  fn generate(&mut self) -> Message {
+    let i = self.rng.gen::<usize>() % self.books.len();
+    if !self.books[i].live {
+      return self.snapshot(i);
+    }
+    let side = self.side();
+    if self.rng.gen::<f64>() < self.trade_probability {
+      if let Some(trade) = self.trade(i, side) {
+        return trade;
+      }
+    }
+    self.update(i, side)
+  }
+}
+
+impl ExtendedStream for Feed {
+  type Item = Message;
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    loop {
+      let now = Instant::now();
+
+      if now >= self.next_arrival {
+        self.next_arrival = now + self.arrival_delay();
+        self.last_sent = now;
+        return Ok(ExtendedAsync::Ready(Some(self.generate())));
+      }
+
+      let mut wake = self.next_arrival;
+      if let Some(interval) = self.heartbeat_interval {
+        if now >= self.last_sent + interval {
+          self.last_sent = now;
+          return Ok(ExtendedAsync::Ready(Some(Message::Heartbeat)));
+        }
+        wake = wake.min(self.last_sent + interval);
+      }
+
+      extended_try_ready!(Sleeper::new(wake - now).extended_poll(task_handle));
+    }
+  }
+}
+
+impl Stream for Feed {
+  type Item = Message;
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+/// Serve a new feed, made by `make_feed`, to every websocket client that
+/// connects to `listener`, with each message sent as a JSON text frame
+pub fn serve<F: FnMut() -> Feed>(listener: TcpListener, handle: Handle, make_feed: F) -> Server<F> {
+  Server{incoming: listener.incoming(), handle, make_feed}
+}
+
+/// A future which accepts websocket clients and streams feeds to them,
+/// and which only resolves if the listener fails
+pub struct Server<F> {
+  incoming:  Incoming,
+  handle:    Handle,
+  make_feed: F,
+}
+
+impl<F: FnMut() -> Feed> Future for Server<F> {
+  type Item = ();
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    loop {
+      let (connection, _address) = match try_ready!(self.incoming.poll()) {
+        Some(connection) => connection,
+        None => return Ok(Async::Ready(())),
+      };
+
+      let feed = (self.make_feed)()
+        .map(|message| websocket::Message::Text(message.to_json().to_string()))
+        .map_err(|void| match void {});
+
+      // a client going away only ends its own connection
+      self.handle.spawn(
+        accept(connection)
+          .and_then(|socket| feed.forward(socket))
+          .map(|_| ())
+          .map_err(|_| ())
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::websocket::client;
+  use tokio_core::net::TcpStream;
+
+  fn sequence(message: &Message) -> Option<(&str, u64)> {
+    match *message {
+      Message::Snapshot{ref symbol, sequence, ..}
+      | Message::Update{ref symbol, sequence, ..}
+      | Message::Trade{ref symbol, sequence, ..} => Some((symbol, sequence)),
+      Message::Heartbeat => None,
+    }
+  }
+
+  fn instant_feed(symbols: &[&str]) -> Feed {
+    Feed::new(symbols).with_arrivals(Duration::new(0, 0), Duration::new(0, 0)).with_seed(1)
+  }
+
+  #[test]
+  fn books_start_with_snapshots_and_count_up() {
+    let messages = instant_feed(&["BTC-USD", "ETH-USD"]).take(500).collect().wait().unwrap();
+
+    let mut last = HashMap::new();
+    for message in &messages {
+      let (symbol, sequence) = sequence(message).unwrap();
+      match last.insert(symbol.to_string(), sequence) {
+        None => assert!(matches!(*message, Message::Snapshot{..}), "first message for {} was {:?}", symbol, message),
+        Some(previous) => assert_eq!(sequence, previous + 1),
+      }
+    }
+
+    assert_eq!(last.len(), 2);
+    assert!(messages.iter().any(|message| matches!(*message, Message::Trade{..})));
+    assert!(messages.iter().any(|message| matches!(*message, Message::Update{..})));
+  }
+
+  #[test]
+  fn books_never_cross() {
+    let mut feed = instant_feed(&["BTC-USD"]).with_trade_probability(0.5);
+    for _ in 0..1000 {
+      feed.generate();
+      let book = &feed.books[0];
+      if let (Some((bid, _)), Some((ask, _))) = (book.best(Side::Bid), book.best(Side::Ask)) {
+        assert!(bid < ask, "book crossed: {} >= {}", bid, ask);
+      }
+    }
+  }
+
+  #[test]
+  fn deepest_books_have_positive_prices() {
+    let mut feed = instant_feed(&["A"]).with_depth(1000);
+    for _ in 0..1000 {
+      feed.generate();
+    }
+    assert!(feed.books[0].best(Side::Bid).is_some());
+  }
+
+  #[test]
+  #[should_panic(expected = "book depth must be at most 1000")]
+  fn depth_is_bounded() {
+    Feed::new(&["A"]).with_depth(20_000);
+  }
+
+  #[test]
+  fn seeded_feeds_repeat() {
+    let a = instant_feed(&["A", "B"]).take(100).collect().wait().unwrap();
+    let b = instant_feed(&["A", "B"]).take(100).collect().wait().unwrap();
+    assert_eq!(a, b);
+  }
+
+  #[test]
+  fn gaps() {
+    let messages = instant_feed(&["A"]).with_gap_probability(0.5).take(100).collect().wait().unwrap();
+    let sequences = messages.iter().filter_map(sequence).map(|(_, sequence)| sequence).collect::<Vec<u64>>();
+    assert!(sequences.windows(2).all(|pair| pair[1] > pair[0]));
+    assert!(sequences.windows(2).any(|pair| pair[1] > pair[0] + 1));
+  }
+
+  #[test]
+  fn heartbeats_fill_quiet_periods() {
+    let feed = Feed::new(&["A"])
+      .with_arrivals(Duration::from_millis(250), Duration::from_millis(250))
+      .with_heartbeat_interval(Duration::from_millis(100));
+    let messages = feed.take(4).collect().wait().unwrap();
+    assert!(matches!(messages[0], Message::Snapshot{..}));
+    assert_eq!(messages[1..3], [Message::Heartbeat, Message::Heartbeat]);
+    assert!(matches!(messages[3], Message::Update{..} | Message::Trade{..}));
+  }
+
+  #[test]
+  fn served_over_websocket() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let address = listener.local_addr().unwrap();
+    handle.spawn(serve(listener, handle.clone(), || instant_feed(&["BTC-USD"])).map_err(|err| panic!("server failed: {}", err)));
+
+    let frames = core.run(
+      TcpStream::connect(&address, &handle)
+        .and_then(|stream| client(stream, "localhost", "/feed"))
+        .and_then(|socket| socket.take(3).collect())
+    ).unwrap();
+
+    let expected = instant_feed(&["BTC-USD"]).take(3).collect().wait().unwrap();
+    let expected = expected.iter().map(|message| websocket::Message::Text(message.to_json().to_string())).collect::<Vec<_>>();
+    assert_eq!(frames, expected);
+
+    match frames[0] {
+      websocket::Message::Text(ref text) => {
+        let value: Value = serde_json::from_str(text).unwrap();
+        assert_eq!(value["type"], "snapshot");
+        assert_eq!(value["symbol"], "BTC-USD");
+        assert_eq!(value["sequence"], 1);
+        assert_eq!(value["bids"].as_array().unwrap().len(), 5);
+      }
+      ref other => panic!("unexpected frame: {:?}", other),
+    }
+  }
+}
@@ -1,55 +1,86 @@
 use common::*;
 use extended::common::*;
 
-/// A future which immediately returns a random `u8`
+/// A future which immediately returns the next value from an iterator, by
+/// default a random `u8`
 #[derive(Debug)]
-pub struct Producer {
-  _private: (),
+pub struct Producer<I = Random> {
+  items: I,
 }
 
 impl Producer {
   pub fn new() -> Producer {
-    Producer{_private: ()}
+    Producer::from_items(Random::new())
+  }
+
+  /// A producer of a random `u8` drawn from a generator seeded with `seed`
+  pub fn with_seed(seed: u64) -> Producer {
+    Producer::from_items(Random::with_seed(seed))
+  }
+}
+
+impl<T, F: FnOnce() -> T> Producer<iter::OnceWith<F>> {
+  /// A producer which returns the value made by `generate`
+  pub fn from_fn(generate: F) -> Producer<iter::OnceWith<F>> {
+    Producer::from_items(iter::once_with(generate))
+  }
+}
+
+impl<I: Iterator> Producer<I> {
+  /// A producer which returns the next value from `items`, which must not be
+  /// empty
+  pub fn from_items(items: I) -> Producer<I> {
+    Producer{items}
   }
 }
 
-impl ExtendedFuture for Producer {
-  type Item = u8;
+impl<I: Iterator> ExtendedFuture for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
 
   fn extended_poll(&mut self, _task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
-    Ok(ExtendedAsync::Ready(random()))
+    Ok(ExtendedAsync::Ready(self.items.next().expect("producer polled after completion")))
   }
 }
 
-impl Future for Producer {
-  type Item = u8;
+impl<I: Iterator> Future for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
   fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
     future_adapter(self)
   }
 }
 
-/// A future which immediately consumes a random `u8`
-pub struct Consumer {
-  _private: (),
+/// A future which immediately consumes a value by passing it to a callback,
+/// which by default discards it
+pub struct Consumer<T = u8, F = fn(T)> {
+  sending: Option<(T, F)>,
 }
 
 impl Consumer {
-  pub fn new(_value: u8) -> Consumer {
-    Consumer{_private: ()}
+  pub fn new(value: u8) -> Consumer {
+    Consumer::with_callback(value, drop)
+  }
+}
+
+impl<T, F: FnOnce(T)> Consumer<T, F> {
+  /// A consumer which passes `value` to `callback`
+  pub fn with_callback(value: T, callback: F) -> Consumer<T, F> {
+    Consumer{sending: Some((value, callback))}
   }
 }
 
-impl ExtendedFuture for Consumer {
+impl<T, F: FnOnce(T)> ExtendedFuture for Consumer<T, F> {
   type Item = ();
   type Error = Void;
   fn extended_poll(&mut self, _task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let (value, callback) = self.sending.take().expect("consumer polled after completion");
+    callback(value);
     Ok(ExtendedAsync::Ready(()))
   }
 }
 
-impl Future for Consumer {
+impl<T, F: FnOnce(T)> Future for Consumer<T, F> {
   type Item = ();
   type Error = Void;
 
@@ -83,6 +114,15 @@ mod tests {
   fn consumer_completes_quickly() {
     let start = Instant::now();
     Consumer::new(0).wait().unwrap();
-    assert!(start.elapsed() < Duration::new(0, 200_0000_000));
+    assert!(start.elapsed() < Duration::new(0, 2_000_000_000));
+  }
+
+  #[test]
+  fn generic() {
+    assert_eq!(Producer::from_fn(|| "hello").wait().unwrap(), "hello");
+    assert_eq!(Producer::with_seed(1).wait().unwrap(), Producer::with_seed(1).wait().unwrap());
+    let mut consumed = None;
+    Consumer::with_callback("hello", |value| consumed = Some(value)).wait().unwrap();
+    assert_eq!(consumed, Some("hello"));
   }
 }
@@ -0,0 +1,121 @@
+use common::*;
+use extended::common::*;
+
+use std::io::{Read, Write};
+use tokio_core::net::TcpStream;
+
+/// The extended API equivalent of a non-blocking `io::Read`
+///
+/// Instead of returning an `io::ErrorKind::WouldBlock` error and leaving it to
+/// the caller to guess that the current task will be notified, implementors
+/// return `NotReady` with an `AgreementToNotify`.
+pub trait ExtendedRead {
+  /// Read bytes into `buf`, returning the number of bytes read, or `0` if the
+  /// end of the input has been reached
+  fn extended_read(&mut self, task_handle: &mut TaskHandle, buf: &mut [u8])
+    -> ExtendedPoll<usize, io::Error>;
+}
+
+/// The extended API equivalent of a non-blocking `io::Write`
+pub trait ExtendedWrite {
+  /// Write bytes from `buf`, returning the number of bytes written
+  fn extended_write(&mut self, task_handle: &mut TaskHandle, buf: &[u8])
+    -> ExtendedPoll<usize, io::Error>;
+
+  /// Ready once all written bytes have been handed off
+  fn extended_flush(&mut self, task_handle: &mut TaskHandle)
+    -> ExtendedPoll<(), io::Error>;
+}
+
+/// Convert the result of a non-blocking IO call made on a reactor-backed
+/// object into an extended poll
+fn reactor_poll<T>(result: io::Result<T>, task_handle: &mut TaskHandle) -> ExtendedPoll<T, io::Error> {
+  match result {
+    Ok(t) => Ok(ExtendedAsync::Ready(t)),
+    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+      // a `WouldBlock` from a reactor-backed object means that the reactor
+      // has registered interest on behalf of the current task, and will
+      // notify it when the object is ready
+      let (_task, agreement_to_notify) = task_handle.i_will_notify();
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+    Err(err) => Err(err),
+  }
+}
+
+impl ExtendedRead for TcpStream {
+  fn extended_read(&mut self, task_handle: &mut TaskHandle, buf: &mut [u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    reactor_poll(self.read(buf), task_handle)
+  }
+}
+
+impl ExtendedWrite for TcpStream {
+  fn extended_write(&mut self, task_handle: &mut TaskHandle, buf: &[u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    reactor_poll(self.write(buf), task_handle)
+  }
+
+  fn extended_flush(&mut self, task_handle: &mut TaskHandle)
+    -> ExtendedPoll<(), io::Error>
+  {
+    reactor_poll(self.flush(), task_handle)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+  use tokio_core::net::TcpListener;
+
+  #[test]
+  fn tcp_round_trip() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    handle.spawn(
+      listener.incoming().into_future()
+        .map_err(|(err, _)| panic!("accept failed: {}", err))
+        .and_then(|(connection, _)| {
+          let (mut stream, _) = connection.unwrap();
+          let mut buf = [0; 5];
+          let mut read = 0;
+          future::poll_fn(move || {
+            while read < buf.len() {
+              let n = try_ready!(read_adapter(&mut stream, &mut buf[read..]));
+              read += n;
+            }
+            let n = try_ready!(write_adapter(&mut stream, &buf));
+            assert_eq!(n, buf.len());
+            Ok(Async::Ready(()))
+          }).map_err(|err: io::Error| panic!("server failed: {}", err))
+        })
+    );
+
+    let client = TcpStream::connect(&address, &handle).and_then(|mut stream| {
+      let mut written = false;
+      let mut buf = [0; 5];
+      let mut read = 0;
+      future::poll_fn(move || {
+        if !written {
+          let n = try_ready!(write_adapter(&mut stream, b"hello"));
+          assert_eq!(n, 5);
+          written = true;
+        }
+        while read < buf.len() {
+          let n = try_ready!(read_adapter(&mut stream, &mut buf[read..]));
+          assert!(n > 0);
+          read += n;
+        }
+        Ok(Async::Ready(buf))
+      })
+    });
+
+    assert_eq!(&core.run(client).unwrap(), b"hello");
+  }
+}
@@ -1,4 +1,5 @@
 use common::*;
+use self::io::{ExtendedRead, ExtendedWrite};
 
 pub mod common {
   pub use extended::{
@@ -15,7 +16,12 @@ pub mod common {
     stream_adapter,
     sink_poll_complete_adapter,
     sink_start_send_adapter,
+    sink_close_adapter,
+    read_adapter,
+    write_adapter,
+    flush_adapter,
   };
+  pub use extended::io::{ExtendedRead, ExtendedWrite};
 }
 
 pub mod sleeper;
@@ -24,7 +30,28 @@ pub mod delayed;
 pub mod instant_series;
 pub mod delayed_series;
 pub mod buffered;
+pub mod batch;
 pub mod adapter;
+pub mod oneshot;
+pub mod mpsc;
+pub mod broadcast;
+pub mod watch;
+pub mod sync;
+pub mod notify;
+pub mod io;
+pub mod pipe;
+pub mod codec;
+pub mod close;
+pub mod split;
+pub mod reconnect;
+pub mod correlator;
+pub mod trace;
+pub mod recorder;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod fault;
+pub mod feed;
+pub mod websocket;
 
 /// A handle to the current task
 pub struct TaskHandle {
@@ -84,6 +111,14 @@ pub trait ExtendedSink {
 
   fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
     -> Result<ExtendedAsync<()>, Self::SinkError>;
+
+  /// Flush any buffered items and release any resources held by the sink.
+  /// By default this is the same as `extended_poll_complete`.
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.extended_poll_complete(task_handle)
+  }
 }
 
 /// The extended API equivalent of the `Stream` trait
@@ -131,6 +166,18 @@ pub fn sink_poll_complete_adapter<T, E>(extended_sink: &mut T) -> Result<Async<(
     }
 }
 
+/// An adaptor function to be used when implementing a standard API `Sink::close`
+/// with an implementation of an extended API `Sink`.
+pub fn sink_close_adapter<T, E>(extended_sink: &mut T) -> Result<Async<()>, E>
+  where T: ExtendedSink<SinkError=E>
+{
+    match extended_sink.extended_close(&mut TaskHandle{_private: ()}) {
+      Ok(ExtendedAsync::Ready(())) => Ok(Async::Ready(())),
+      Ok(ExtendedAsync::NotReady(_)) => Ok(Async::NotReady),
+      Err(err) => Err(err),
+    }
+}
+
 /// An adaptor function to be used when implementing a standard API `Sink::start_send`
 /// with an implementation of an extended API `Sink`.
 pub fn sink_start_send_adapter<T, I, E>(extended_sink: &mut T, item: I) -> Result<AsyncSink<I>, E>
@@ -143,5 +190,41 @@ pub fn sink_start_send_adapter<T, I, E>(extended_sink: &mut T, item: I) -> Resul
     }
 }
 
+/// An adaptor function to be used when polling an extended API `ExtendedRead`
+/// from code using the standard API.
+pub fn read_adapter<T>(extended_read: &mut T, buf: &mut [u8]) -> Result<Async<usize>, ::std::io::Error>
+  where T: ExtendedRead
+{
+    match extended_read.extended_read(&mut TaskHandle{_private: ()}, buf) {
+      Ok(ExtendedAsync::Ready(n)) => Ok(Async::Ready(n)),
+      Ok(ExtendedAsync::NotReady(_)) => Ok(Async::NotReady),
+      Err(err) => Err(err),
+    }
+}
+
+/// An adaptor function to be used when polling an extended API
+/// `ExtendedWrite::extended_write` from code using the standard API.
+pub fn write_adapter<T>(extended_write: &mut T, buf: &[u8]) -> Result<Async<usize>, ::std::io::Error>
+  where T: ExtendedWrite
+{
+    match extended_write.extended_write(&mut TaskHandle{_private: ()}, buf) {
+      Ok(ExtendedAsync::Ready(n)) => Ok(Async::Ready(n)),
+      Ok(ExtendedAsync::NotReady(_)) => Ok(Async::NotReady),
+      Err(err) => Err(err),
+    }
+}
+
+/// An adaptor function to be used when polling an extended API
+/// `ExtendedWrite::extended_flush` from code using the standard API.
+pub fn flush_adapter<T>(extended_write: &mut T) -> Result<Async<()>, ::std::io::Error>
+  where T: ExtendedWrite
+{
+    match extended_write.extended_flush(&mut TaskHandle{_private: ()}) {
+      Ok(ExtendedAsync::Ready(())) => Ok(Async::Ready(())),
+      Ok(ExtendedAsync::NotReady(_)) => Ok(Async::NotReady),
+      Err(err) => Err(err),
+    }
+}
+
 /// The extended API version of `Poll`
 pub type ExtendedPoll<Item, Error> = Result<ExtendedAsync<Item>, Error>;
@@ -0,0 +1,378 @@
+use common::*;
+use extended::common::*;
+
+/// The error produced when sending to a channel whose receiver has been
+/// dropped, containing the item that could not be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "mpsc receiver dropped")
+  }
+}
+
+struct State<T> {
+  buffer:        VecDeque<T>,
+  capacity:      Option<usize>,
+  senders:       usize,
+  next_id:       usize,
+  receiver_gone: bool,
+  receiver_task: Option<Task>,
+  /// senders waiting for capacity, in the order that they arrived
+  parked:        VecDeque<(usize, Task)>,
+  /// woken senders for which a slot in the buffer has been reserved
+  granted:       HashSet<usize>,
+}
+
+impl<T> State<T> {
+  fn has_capacity(&self) -> bool {
+    match self.capacity {
+      Some(capacity) => self.buffer.len() + self.granted.len() < capacity,
+      None => true,
+    }
+  }
+
+  fn is_parked(&self, id: usize) -> bool {
+    self.parked.iter().any(|&(parked, _)| parked == id)
+  }
+
+  fn park(&mut self, id: usize, task: Task) {
+    match self.parked.iter_mut().find(|&&mut (parked, _)| parked == id) {
+      Some(entry) => entry.1 = task,
+      None => self.parked.push_back((id, task)),
+    }
+  }
+
+  /// Reserve a freed slot for the longest waiting sender, returning its task
+  /// so that it can be notified
+  fn grant(&mut self) -> Option<Task> {
+    let (id, task) = self.parked.pop_front()?;
+    self.granted.insert(id);
+    Some(task)
+  }
+}
+
+fn new<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+  let state = Arc::new(Mutex::new(State {
+    buffer:        VecDeque::new(),
+    capacity,
+    senders:       1,
+    next_id:       1,
+    receiver_gone: false,
+    receiver_task: None,
+    parked:        VecDeque::new(),
+    granted:       HashSet::new(),
+  }));
+
+  (Sender{state: state.clone(), id: 0}, Receiver{state})
+}
+
+/// Create a channel which buffers up to `capacity` items, after which senders
+/// must wait for the receiver to catch up
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(capacity > 0, "mpsc channel capacity must be greater than zero");
+  new(Some(capacity))
+}
+
+/// Create a channel which buffers an unlimited number of items, and whose
+/// senders are thus never made to wait
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+  new(None)
+}
+
+/// The sending half of an mpsc channel, which may be cloned to create
+/// additional senders
+///
+/// When the channel is full, senders are parked and then woken one at a time,
+/// in the order they arrived, as the receiver frees up capacity.
+pub struct Sender<T> {
+  state: Arc<Mutex<State<T>>>,
+  id:    usize,
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    let mut state = self.state.lock().unwrap();
+    let id = state.next_id;
+    state.next_id += 1;
+    state.senders += 1;
+    Sender{state: self.state.clone(), id}
+  }
+}
+
+impl<T> ExtendedSink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = SendError<T>;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    let mut state = self.state.lock().unwrap();
+
+    if state.receiver_gone {
+      return Err(SendError(item));
+    }
+
+    let has_slot = if state.granted.remove(&self.id) {
+      true
+    } else if state.is_parked(self.id) {
+      false
+    } else {
+      // don't jump ahead of senders that are already waiting
+      state.parked.is_empty() && state.has_capacity()
+    };
+
+    if has_slot {
+      state.buffer.push_back(item);
+      let task = state.receiver_task.take();
+      drop(state);
+      if let Some(task) = task {
+        task.notify();
+      }
+      Ok(ExtendedAsyncSink::Ready)
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.park(self.id, task);
+      Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify))
+    }
+  }
+
+  fn extended_poll_complete(&mut self, _task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<T> Sink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = SendError<T>;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut tasks = Vec::new();
+    {
+      let mut state = self.state.lock().unwrap();
+      state.senders -= 1;
+
+      if let Some(index) = state.parked.iter().position(|&(parked, _)| parked == self.id) {
+        state.parked.remove(index);
+      }
+
+      // pass on a slot that was reserved for us
+      if state.granted.remove(&self.id) {
+        tasks.extend(state.grant());
+      }
+
+      if state.senders == 0 {
+        tasks.extend(state.receiver_task.take());
+      }
+    }
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+}
+
+/// The receiving half of an mpsc channel, a stream which ends once every
+/// sender has been dropped and all buffered items have been received
+pub struct Receiver<T> {
+  state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> ExtendedStream for Receiver<T> {
+  type Item = T;
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    let mut state = self.state.lock().unwrap();
+
+    if let Some(item) = state.buffer.pop_front() {
+      let task = state.grant();
+      drop(state);
+      if let Some(task) = task {
+        task.notify();
+      }
+      Ok(ExtendedAsync::Ready(Some(item)))
+    } else if state.senders == 0 {
+      Ok(ExtendedAsync::Ready(None))
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.receiver_task = Some(task);
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+}
+
+impl<T> Stream for Receiver<T> {
+  type Item = T;
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let tasks = {
+      let mut state = self.state.lock().unwrap();
+      state.receiver_gone = true;
+      state.parked.drain(..).map(|(_, task)| task).collect::<Vec<Task>>()
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::{future, stream};
+
+  fn ready<T>(result: StartSend<T, SendError<T>>) -> bool {
+    match result {
+      Ok(AsyncSink::Ready) => true,
+      Ok(AsyncSink::NotReady(_)) => false,
+      Err(_) => panic!("receiver dropped"),
+    }
+  }
+
+  #[test]
+  fn senders_are_woken_in_arrival_order() {
+    let (mut first, mut receiver) = channel(1);
+    let mut second = first.clone();
+    let mut third = first.clone();
+    let mut late = first.clone();
+
+    future::lazy(|| {
+      assert!(ready(first.start_send(0)));
+      assert!(!ready(second.start_send(1)));
+      assert!(!ready(third.start_send(2)));
+
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(0))));
+
+      // the freed slot is reserved for `second`, so neither a new sender nor
+      // one that arrived later may take it
+      assert!(!ready(late.start_send(3)));
+      assert!(!ready(third.start_send(2)));
+      assert!(ready(second.start_send(1)));
+
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(1))));
+      assert!(!ready(late.start_send(3)));
+      assert!(ready(third.start_send(2)));
+
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(2))));
+      assert!(ready(late.start_send(3)));
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(3))));
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn dropped_sender_passes_on_its_slot() {
+    let (mut first, mut receiver) = channel(1);
+    let mut second = first.clone();
+    let mut third = first.clone();
+
+    future::lazy(|| {
+      assert!(ready(first.start_send(0)));
+      assert!(!ready(second.start_send(1)));
+      assert!(!ready(third.start_send(2)));
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(0))));
+
+      drop(second);
+      assert!(ready(third.start_send(2)));
+      assert_eq!(receiver.poll(), Ok(Async::Ready(Some(2))));
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn many_senders_on_core() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (sender, receiver) = channel(2);
+    let senders = 10;
+    let items = 100;
+
+    for s in 0..senders {
+      handle.spawn(
+        sender.clone()
+          .send_all(stream::iter_ok((0..items).map(move |i| s * items + i)))
+          .map(|_| ())
+          .map_err(|err| panic!("got error: {}", err))
+      );
+    }
+    drop(sender);
+
+    let mut received = core.run(receiver.collect()).unwrap();
+
+    // each sender's items arrive in the order they were sent
+    for s in 0..senders {
+      let own = received.iter().cloned().filter(|value| value / items == s).collect::<Vec<u64>>();
+      assert_eq!(own, (0..items).map(|i| s * items + i).collect::<Vec<u64>>());
+    }
+
+    received.sort();
+    assert_eq!(received, (0..senders * items).collect::<Vec<u64>>());
+  }
+
+  #[test]
+  fn receiving_from_thread() {
+    let (sender, receiver) = channel(1);
+    thread::spawn(move || {
+      let _ = sender.send_all(stream::iter_ok(0..100u8)).wait().unwrap();
+    });
+    assert_eq!(receiver.collect().wait().unwrap(), (0..100).collect::<Vec<u8>>());
+  }
+
+  #[test]
+  fn unbounded_never_waits() {
+    let (mut sender, receiver) = unbounded();
+    future::lazy(|| {
+      for i in 0..1000 {
+        assert!(ready(sender.start_send(i)));
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+    drop(sender);
+    assert_eq!(receiver.collect().wait().unwrap(), (0..1000).collect::<Vec<u32>>());
+  }
+
+  #[test]
+  fn send_after_receiver_dropped() {
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    match sender.send(7u8).wait() {
+      Err(SendError(7)) => {}
+      _ => panic!("send should fail"),
+    }
+  }
+
+  #[test]
+  fn receiver_drop_wakes_waiting_senders() {
+    let (sender, receiver) = channel(1);
+    let waiting = thread::spawn(move || sender.send_all(stream::iter_ok(0..10u8)).wait().is_err());
+    thread::sleep(Duration::new(0, 100_000_000));
+    drop(receiver);
+    assert!(waiting.join().unwrap());
+  }
+}
@@ -0,0 +1,269 @@
+use common::*;
+use extended::common::*;
+
+struct State {
+  permit:   bool,
+  next_id:  usize,
+  /// registered `Notified` futures, in the order that they first waited
+  waiting:  VecDeque<(usize, Task)>,
+  /// woken `Notified` futures which have not yet been polled, and whether
+  /// they were woken by `notify_one`, and should thus pass the notification
+  /// on if dropped
+  notified: HashMap<usize, bool>,
+}
+
+impl State {
+  fn notify_one(&mut self) -> Option<Task> {
+    match self.waiting.pop_front() {
+      Some((id, task)) => {
+        self.notified.insert(id, true);
+        Some(task)
+      }
+      None => {
+        self.permit = true;
+        None
+      }
+    }
+  }
+}
+
+/// A way to wake tasks without sending them a value, for when application
+/// code needs to say "something changed, poll again"
+///
+/// Cloning a `Notify` produces another handle to the same set of waiters.
+#[derive(Clone)]
+pub struct Notify {
+  state: Arc<Mutex<State>>,
+}
+
+impl Notify {
+  pub fn new() -> Notify {
+    Notify {
+      state: Arc::new(Mutex::new(State {
+        permit:   false,
+        next_id:  0,
+        waiting:  VecDeque::new(),
+        notified: HashMap::new(),
+      })),
+    }
+  }
+
+  /// Wake the longest waiting `Notified` future
+  ///
+  /// If nothing is waiting, a permit is stored, and the next `Notified`
+  /// future to be polled will complete immediately. Permits do not
+  /// accumulate, so calling this several times with nothing waiting stores
+  /// only one.
+  pub fn notify_one(&self) {
+    let task = self.state.lock().unwrap().notify_one();
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+
+  /// Wake every `Notified` future which is currently waiting
+  ///
+  /// No permit is stored, so futures which are polled for the first time
+  /// after this call will wait for the next notification.
+  pub fn notify_waiters(&self) {
+    let tasks = {
+      let mut state = self.state.lock().unwrap();
+      let mut tasks = Vec::new();
+      while let Some((id, task)) = state.waiting.pop_front() {
+        state.notified.insert(id, false);
+        tasks.push(task);
+      }
+      tasks
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+
+  /// Returns a future which completes on the next notification
+  pub fn notified(&self) -> Notified {
+    Notified{state: self.state.clone(), id: None}
+  }
+}
+
+/// A future which completes when its `Notify` is notified
+///
+/// A `Notified` starts waiting when it is first polled. If it is dropped
+/// after being woken by `notify_one` but before being polled again, the
+/// notification is passed on to the next waiter.
+pub struct Notified {
+  state: Arc<Mutex<State>>,
+  id:    Option<usize>,
+}
+
+impl ExtendedFuture for Notified {
+  type Item = ();
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    let ready = match self.id {
+      Some(id) => state.notified.remove(&id).is_some(),
+      None => {
+        let permit = state.permit;
+        state.permit = false;
+        permit
+      }
+    };
+
+    if ready {
+      self.id = None;
+      return Ok(ExtendedAsync::Ready(()));
+    }
+
+    let (task, agreement_to_notify) = task_handle.i_will_notify();
+    match self.id {
+      Some(id) => {
+        let entry = state.waiting.iter_mut().find(|&&mut (waiting, _)| waiting == id).unwrap();
+        entry.1 = task;
+      }
+      None => {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back((id, task));
+        self.id = Some(id);
+      }
+    }
+    Ok(ExtendedAsync::NotReady(agreement_to_notify))
+  }
+}
+
+impl Future for Notified {
+  type Item = ();
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+impl Drop for Notified {
+  fn drop(&mut self) {
+    if let Some(id) = self.id {
+      let task = {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.waiting.iter().position(|&(waiting, _)| waiting == id) {
+          state.waiting.remove(index);
+          None
+        } else if let Some(true) = state.notified.remove(&id) {
+          state.notify_one()
+        } else {
+          None
+        }
+      };
+
+      if let Some(task) = task {
+        task.notify();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+
+  fn ready(poll: Poll<(), Void>) -> bool {
+    match poll {
+      Ok(Async::Ready(())) => true,
+      Ok(Async::NotReady) => false,
+      Err(err) => match err {},
+    }
+  }
+
+  #[test]
+  fn notification_before_waiting_is_kept() {
+    let notify = Notify::new();
+    notify.notify_one();
+    notify.notify_one();
+    notify.notified().wait().unwrap();
+
+    future::lazy(|| {
+      // permits don't accumulate
+      assert!(!ready(notify.notified().poll()));
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn notify_one_wakes_longest_waiting() {
+    let notify = Notify::new();
+
+    future::lazy(|| {
+      let mut first = notify.notified();
+      let mut second = notify.notified();
+      assert!(!ready(first.poll()));
+      assert!(!ready(second.poll()));
+
+      notify.notify_one();
+      assert!(!ready(second.poll()));
+      assert!(ready(first.poll()));
+
+      notify.notify_one();
+      assert!(ready(second.poll()));
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn notify_waiters_wakes_all_and_keeps_nothing() {
+    let notify = Notify::new();
+
+    future::lazy(|| {
+      let mut first = notify.notified();
+      let mut second = notify.notified();
+      assert!(!ready(first.poll()));
+      assert!(!ready(second.poll()));
+
+      notify.notify_waiters();
+      assert!(ready(first.poll()));
+      assert!(ready(second.poll()));
+      assert!(!ready(notify.notified().poll()));
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn dropped_waiter_passes_notification_on() {
+    let notify = Notify::new();
+
+    future::lazy(|| {
+      let mut first = notify.notified();
+      let mut second = notify.notified();
+      assert!(!ready(first.poll()));
+      assert!(!ready(second.poll()));
+
+      notify.notify_one();
+      drop(first);
+      assert!(ready(second.poll()));
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn wake_task_from_thread() {
+    let mut core = Core::new().unwrap();
+    let notify = Notify::new();
+    let notified = notify.notified();
+
+    thread::spawn(move || {
+      thread::sleep(Duration::new(0, 100_000_000));
+      notify.notify_one();
+    });
+
+    let start = Instant::now();
+    core.run(notified).unwrap();
+    assert!(start.elapsed() > Duration::new(0, 50_000_000));
+  }
+}
@@ -0,0 +1,233 @@
+use common::*;
+use extended::common::*;
+
+/// The error produced by a `Receiver` whose `Sender` was dropped without
+/// sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "oneshot sender dropped without sending a value")
+  }
+}
+
+struct Inner<T> {
+  value:         Option<T>,
+  sender_done:   bool,
+  receiver_gone: bool,
+  sender_task:   Option<Task>,
+  receiver_task: Option<Task>,
+}
+
+/// Create a channel for sending a single value from one task, or thread, to
+/// another
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+  let inner = Arc::new(Mutex::new(Inner {
+    value:         None,
+    sender_done:   false,
+    receiver_gone: false,
+    sender_task:   None,
+    receiver_task: None,
+  }));
+
+  (Sender{inner: inner.clone()}, Receiver{inner})
+}
+
+/// The sending half of a oneshot channel
+pub struct Sender<T> {
+  inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+  /// Send `value` to the receiver, or give it back if the receiver has
+  /// already been dropped
+  pub fn send(self, value: T) -> Result<(), T> {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.receiver_gone {
+      return Err(value);
+    }
+    inner.value = Some(value);
+    Ok(())
+  }
+
+  /// Returns true if the receiver has been dropped
+  pub fn is_canceled(&self) -> bool {
+    self.inner.lock().unwrap().receiver_gone
+  }
+
+  /// Ready when the receiver has been dropped, so that whoever is holding
+  /// the sender can stop working on a value that nobody wants
+  pub fn extended_poll_cancel(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<(), Void> {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.receiver_gone {
+      Ok(ExtendedAsync::Ready(()))
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      inner.sender_task = Some(task);
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+
+  /// The standard API version of `extended_poll_cancel`
+  pub fn poll_cancel(&mut self) -> Poll<(), Void> {
+    match self.extended_poll_cancel(&mut TaskHandle{_private: ()})? {
+      ExtendedAsync::Ready(()) => Ok(Async::Ready(())),
+      ExtendedAsync::NotReady(_) => Ok(Async::NotReady),
+    }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let task = {
+      let mut inner = self.inner.lock().unwrap();
+      inner.sender_done = true;
+      inner.receiver_task.take()
+    };
+
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+}
+
+/// The receiving half of a oneshot channel, a future which resolves to the
+/// sent value
+pub struct Receiver<T> {
+  inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> ExtendedFuture for Receiver<T> {
+  type Item = T;
+  type Error = Canceled;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let mut inner = self.inner.lock().unwrap();
+    if let Some(value) = inner.value.take() {
+      Ok(ExtendedAsync::Ready(value))
+    } else if inner.sender_done {
+      Err(Canceled)
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      inner.receiver_task = Some(task);
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+}
+
+impl<T> Future for Receiver<T> {
+  type Item = T;
+  type Error = Canceled;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let task = {
+      let mut inner = self.inner.lock().unwrap();
+      inner.receiver_gone = true;
+      inner.sender_task.take()
+    };
+
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+
+  const RACE_ITERATIONS: usize = 1000;
+
+  #[test]
+  fn send_then_receive() {
+    let (sender, receiver) = channel();
+    sender.send(7u8).unwrap();
+    assert_eq!(receiver.wait(), Ok(7));
+  }
+
+  #[test]
+  fn receive_on_core_from_thread() {
+    let mut core = Core::new().unwrap();
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+      thread::sleep(Duration::new(0, 100_000_000));
+      sender.send(7u8).unwrap();
+    });
+    assert_eq!(core.run(receiver), Ok(7));
+  }
+
+  #[test]
+  fn sender_dropped() {
+    let (sender, receiver) = channel::<u8>();
+    drop(sender);
+    assert_eq!(receiver.wait(), Err(Canceled));
+  }
+
+  #[test]
+  fn send_after_receiver_dropped() {
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert!(sender.is_canceled());
+    assert_eq!(sender.send(7u8), Err(7));
+  }
+
+  #[test]
+  fn poll_cancel_notices_receiver_drop() {
+    let (mut sender, receiver) = channel::<u8>();
+    thread::spawn(move || {
+      thread::sleep(Duration::new(0, 100_000_000));
+      drop(receiver);
+    });
+    future::poll_fn(|| sender.poll_cancel()).wait().unwrap();
+    assert!(sender.is_canceled());
+  }
+
+  #[test]
+  fn race_send_with_waiting_receiver() {
+    for i in 0..RACE_ITERATIONS {
+      let (sender, receiver) = channel();
+      thread::spawn(move || sender.send(i).unwrap());
+      assert_eq!(receiver.wait(), Ok(i));
+    }
+  }
+
+  #[test]
+  fn race_sender_dropped_with_waiting_receiver() {
+    for _ in 0..RACE_ITERATIONS {
+      let (sender, receiver) = channel::<usize>();
+      thread::spawn(move || drop(sender));
+      assert_eq!(receiver.wait(), Err(Canceled));
+    }
+  }
+
+  #[test]
+  fn race_send_with_receiver_dropped() {
+    for _ in 0..RACE_ITERATIONS {
+      let value = Arc::new(());
+      let (sender, receiver) = channel();
+      let dropper = thread::spawn(move || drop(receiver));
+      // the value is either handed back or dropped along with the channel,
+      // but never leaked
+      let _ = sender.send(value.clone());
+      dropper.join().unwrap();
+      assert_eq!(Arc::strong_count(&value), 1);
+    }
+  }
+
+  #[test]
+  fn race_poll_cancel_with_receiver_dropped() {
+    for _ in 0..RACE_ITERATIONS {
+      let (mut sender, receiver) = channel::<usize>();
+      thread::spawn(move || drop(receiver));
+      future::poll_fn(|| sender.poll_cancel()).wait().unwrap();
+    }
+  }
+}
@@ -0,0 +1,255 @@
+use common::*;
+use extended::common::*;
+
+struct State {
+  buffer:        VecDeque<u8>,
+  capacity:      usize,
+  writer_closed: bool,
+  reader_closed: bool,
+  reader_task:   Option<Task>,
+  writer_task:   Option<Task>,
+}
+
+/// Create an in-memory, unidirectional byte pipe which buffers up to
+/// `capacity` bytes
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+  assert!(capacity > 0, "pipe capacity must be greater than zero");
+
+  let state = Arc::new(Mutex::new(State {
+    buffer:        VecDeque::with_capacity(capacity),
+    capacity,
+    writer_closed: false,
+    reader_closed: false,
+    reader_task:   None,
+    writer_task:   None,
+  }));
+
+  (PipeWriter{state: state.clone()}, PipeReader{state})
+}
+
+/// Create a pair of connected in-memory duplex byte streams, each of which
+/// buffers up to `capacity` bytes in each direction
+pub fn duplex(capacity: usize) -> (Duplex, Duplex) {
+  let (a_writer, b_reader) = pipe(capacity);
+  let (b_writer, a_reader) = pipe(capacity);
+  (
+    Duplex{reader: a_reader, writer: a_writer},
+    Duplex{reader: b_reader, writer: b_writer},
+  )
+}
+
+/// The writing end of a pipe, which signals end of input to the reader when
+/// dropped
+pub struct PipeWriter {
+  state: Arc<Mutex<State>>,
+}
+
+impl ExtendedWrite for PipeWriter {
+  fn extended_write(&mut self, task_handle: &mut TaskHandle, buf: &[u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    let mut state = self.state.lock().unwrap();
+
+    if state.reader_closed {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe reader dropped"));
+    }
+
+    if buf.is_empty() {
+      return Ok(ExtendedAsync::Ready(0));
+    }
+
+    let available = state.capacity - state.buffer.len();
+    if available == 0 {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.writer_task = Some(task);
+      return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+    }
+
+    let n = available.min(buf.len());
+    state.buffer.extend(&buf[..n]);
+    let task = state.reader_task.take();
+    drop(state);
+    if let Some(task) = task {
+      task.notify();
+    }
+    Ok(ExtendedAsync::Ready(n))
+  }
+
+  fn extended_flush(&mut self, _task_handle: &mut TaskHandle)
+    -> ExtendedPoll<(), io::Error>
+  {
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl Drop for PipeWriter {
+  fn drop(&mut self) {
+    let task = {
+      let mut state = self.state.lock().unwrap();
+      state.writer_closed = true;
+      state.reader_task.take()
+    };
+
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+}
+
+/// The reading end of a pipe
+pub struct PipeReader {
+  state: Arc<Mutex<State>>,
+}
+
+impl ExtendedRead for PipeReader {
+  fn extended_read(&mut self, task_handle: &mut TaskHandle, buf: &mut [u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    let mut state = self.state.lock().unwrap();
+
+    if state.buffer.is_empty() {
+      if state.writer_closed || buf.is_empty() {
+        return Ok(ExtendedAsync::Ready(0));
+      }
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.reader_task = Some(task);
+      return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+    }
+
+    let n = state.buffer.len().min(buf.len());
+    for (slot, byte) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+      *slot = byte;
+    }
+    let task = state.writer_task.take();
+    drop(state);
+    if let Some(task) = task {
+      task.notify();
+    }
+    Ok(ExtendedAsync::Ready(n))
+  }
+}
+
+impl Drop for PipeReader {
+  fn drop(&mut self) {
+    let task = {
+      let mut state = self.state.lock().unwrap();
+      state.reader_closed = true;
+      state.writer_task.take()
+    };
+
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+}
+
+/// One end of an in-memory duplex byte stream
+pub struct Duplex {
+  reader: PipeReader,
+  writer: PipeWriter,
+}
+
+impl Duplex {
+  /// Split into separate reading and writing halves
+  pub fn split(self) -> (PipeWriter, PipeReader) {
+    (self.writer, self.reader)
+  }
+}
+
+impl ExtendedRead for Duplex {
+  fn extended_read(&mut self, task_handle: &mut TaskHandle, buf: &mut [u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    self.reader.extended_read(task_handle, buf)
+  }
+}
+
+impl ExtendedWrite for Duplex {
+  fn extended_write(&mut self, task_handle: &mut TaskHandle, buf: &[u8])
+    -> ExtendedPoll<usize, io::Error>
+  {
+    self.writer.extended_write(task_handle, buf)
+  }
+
+  fn extended_flush(&mut self, task_handle: &mut TaskHandle)
+    -> ExtendedPoll<(), io::Error>
+  {
+    self.writer.extended_flush(task_handle)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+
+  #[test]
+  fn bytes_arrive_in_order() {
+    let (mut writer, mut reader) = pipe(4);
+
+    let writing = thread::spawn(move || {
+      let data = (0..100).collect::<Vec<u8>>();
+      let mut written = 0;
+      future::poll_fn(|| {
+        while written < data.len() {
+          written += try_ready!(write_adapter(&mut writer, &data[written..]));
+        }
+        Ok::<Async<()>, io::Error>(Async::Ready(()))
+      }).wait().unwrap();
+    });
+
+    let mut received = Vec::new();
+    future::poll_fn(|| {
+      loop {
+        let mut buf = [0; 3];
+        match try_ready!(read_adapter(&mut reader, &mut buf)) {
+          0 => return Ok::<Async<()>, io::Error>(Async::Ready(())),
+          n => received.extend_from_slice(&buf[..n]),
+        }
+      }
+    }).wait().unwrap();
+
+    writing.join().unwrap();
+    assert_eq!(received, (0..100).collect::<Vec<u8>>());
+  }
+
+  #[test]
+  fn writer_waits_when_full() {
+    let (mut writer, _reader) = pipe(4);
+    future::lazy(|| {
+      assert_eq!(write_adapter(&mut writer, &[0; 8]).unwrap(), Async::Ready(4));
+      assert_eq!(write_adapter(&mut writer, &[0; 8]).unwrap(), Async::NotReady);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn dropping_writer_ends_input() {
+    let (writer, mut reader) = pipe(4);
+    drop(writer);
+    assert_eq!(read_adapter(&mut reader, &mut [0; 4]).unwrap(), Async::Ready(0));
+  }
+
+  #[test]
+  fn dropping_reader_breaks_pipe() {
+    let (mut writer, reader) = pipe(4);
+    drop(reader);
+    let err = write_adapter(&mut writer, &[0]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+  }
+
+  #[test]
+  fn duplex_is_connected_both_ways() {
+    let (mut a, mut b) = duplex(8);
+    future::lazy(|| {
+      let mut buf = [0; 8];
+      assert_eq!(write_adapter(&mut a, b"ping").unwrap(), Async::Ready(4));
+      assert_eq!(read_adapter(&mut b, &mut buf).unwrap(), Async::Ready(4));
+      assert_eq!(&buf[..4], b"ping");
+      assert_eq!(write_adapter(&mut b, b"pong").unwrap(), Async::Ready(4));
+      assert_eq!(read_adapter(&mut a, &mut buf).unwrap(), Async::Ready(4));
+      assert_eq!(&buf[..4], b"pong");
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
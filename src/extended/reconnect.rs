@@ -0,0 +1,492 @@
+use common::*;
+use extended::common::*;
+use extended::adapter::{Adapter, AdapterError, Error, Handler, Processor};
+use extended::sleeper::Sleeper;
+use extended::trace::{Event as TraceEvent, Tracer};
+
+/// A factory for connections, each of which is an underlying stream and sink
+/// for an `Adapter`
+pub trait Connect {
+  type Stream: ExtendedStream;
+  type Sink: ExtendedSink;
+  type Error: fmt::Display;
+
+  /// Make progress on establishing a new connection. Once a connection has
+  /// been returned, the next call starts a new connection attempt.
+  fn poll_connect(&mut self, task_handle: &mut TaskHandle)
+    -> ExtendedPoll<(Self::Stream, Self::Sink), Self::Error>;
+}
+
+/// Jittered exponential backoff between connection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+  initial:  Duration,
+  max:      Duration,
+  attempts: u32,
+  rng:      Random,
+}
+
+impl Backoff {
+  /// Wait around `initial` after the first failure, doubling with each
+  /// consecutive failure up to `max`
+  pub fn new(initial: Duration, max: Duration) -> Backoff {
+    Backoff{initial, max, attempts: 0, rng: Random::new()}
+  }
+
+  /// Draw jitter from a generator seeded with `seed`, so that the sequence
+  /// of delays is the same on every run
+  pub fn with_seed(self, seed: u64) -> Backoff {
+    Backoff{rng: Random::with_seed(seed), ..self}
+  }
+
+  /// The delay before the next attempt, scaled by a random factor between
+  /// one half and one so that many clients don't reconnect in lockstep
+  pub fn next_delay(&mut self) -> Duration {
+    let base = self.initial
+      .checked_mul(1 << self.attempts.min(16))
+      .unwrap_or(self.max)
+      .min(self.max);
+    self.attempts += 1;
+    base.mul_f64(0.5 + self.rng.gen::<f64>() / 2.0)
+  }
+
+  /// Start again from `initial`, after a successful connection
+  pub fn reset(&mut self) {
+    self.attempts = 0;
+  }
+}
+
+/// An item yielded by a `Reconnecting` adapter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<T> {
+  /// An item from the current connection
+  Item(T),
+  /// The connection was lost and a new one has been established, so any
+  /// state that depends on the old connection may be stale
+  Reconnected,
+}
+
+enum State {
+  /// About to make a new connection attempt
+  Idle,
+  /// Waiting to make a new connection attempt
+  Waiting(Sleeper),
+  /// Polling the connection factory
+  Connecting,
+  Connected,
+}
+
+/// An `Adapter` which reconnects whenever its underlying stream ends or its
+/// underlying stream or sink fails
+///
+/// Reconnection attempts are spaced out using a `Backoff`, and after each
+/// connection is established the configured subscription messages are sent
+/// to the new sink. Only processor errors are returned to the consumer.
+pub struct Reconnecting<C: Connect, P: Processor, H = ()> {
+  connect:       C,
+  adapter:       Option<Adapter<C::Stream, C::Sink, P, H>>,
+  /// the processor and handler, until the first connection is established
+  parts:         Option<(P, H)>,
+  subscriptions: Vec<P::Output>,
+  backoff:       Backoff,
+  state:         State,
+  /// a `Reconnected` event is waiting to be yielded
+  reconnected:   bool,
+  tracer:        Tracer,
+}
+
+impl<C, P> Reconnecting<C, P>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+{
+  pub fn new(connect: C, processor: P) -> Reconnecting<C, P> {
+    Reconnecting::with_handler(connect, processor, ())
+  }
+}
+
+impl<C, P, H> Reconnecting<C, P, H>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+        H: Handler<P::Input, P::Output>,
+{
+  pub fn with_handler(connect: C, processor: P, handler: H) -> Reconnecting<C, P, H> {
+    Reconnecting {
+      connect,
+      adapter:       None,
+      parts:         Some((processor, handler)),
+      subscriptions: Vec::new(),
+      backoff:       Backoff::new(Duration::from_millis(100), Duration::from_secs(30)),
+      state:         State::Idle,
+      reconnected:   false,
+      tracer:        Tracer::none(),
+    }
+  }
+
+  /// Messages to send to the underlying sink every time a connection is
+  /// established
+  pub fn with_subscriptions(self, subscriptions: Vec<P::Output>) -> Reconnecting<C, P, H> {
+    Reconnecting{subscriptions, ..self}
+  }
+
+  pub fn with_backoff(self, backoff: Backoff) -> Reconnecting<C, P, H> {
+    Reconnecting{backoff, ..self}
+  }
+
+  /// Report connection failures and reconnection attempts, along with
+  /// everything reported by the underlying `Adapter`, to `tracer`
+  pub fn with_tracer(self, tracer: Tracer) -> Reconnecting<C, P, H> {
+    Reconnecting{tracer, ..self}
+  }
+
+  /// Drive the state machine until we have a connection
+  fn poll_connected(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<(), P::Error> {
+    loop {
+      match self.state {
+        State::Connected => return Ok(ExtendedAsync::Ready(())),
+        State::Idle => self.state = State::Connecting,
+        State::Waiting(ref mut sleeper) => {
+          if let Ok(ExtendedAsync::NotReady(agreement_to_notify)) = sleeper.extended_poll(task_handle) {
+            return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+          }
+          self.state = State::Connecting;
+        }
+        State::Connecting => match self.connect.poll_connect(task_handle) {
+          Ok(ExtendedAsync::Ready((stream, sink))) => self.connected(stream, sink),
+          Ok(ExtendedAsync::NotReady(agreement_to_notify)) => {
+            return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+          }
+          Err(err) => {
+            self.tracer.emit("reconnect", TraceEvent::ConnectFailed{reason: err.to_string()});
+            self.disconnected();
+          }
+        },
+      }
+    }
+  }
+
+  fn connected(&mut self, stream: C::Stream, sink: C::Sink) {
+    self.backoff.reset();
+    self.state = State::Connected;
+
+    let adapter = match self.adapter {
+      Some(ref mut adapter) => {
+        adapter.reconnect(stream, sink);
+        self.reconnected = true;
+        adapter
+      }
+      None => {
+        let (processor, handler) = self.parts.take().unwrap();
+        self.adapter = Some(Adapter::with_handler(stream, sink, processor, handler).with_tracer(self.tracer.clone()));
+        self.adapter.as_mut().unwrap()
+      }
+    };
+
+    let mut control = adapter.control();
+    for subscription in &self.subscriptions {
+      if control.send(subscription.clone()).is_err() {
+        unreachable!("reconnecting adapters have no capacity");
+      }
+    }
+  }
+
+  fn disconnected(&mut self) {
+    let delay = self.backoff.next_delay();
+    self.tracer.emit("reconnect", TraceEvent::Reconnecting{delay});
+    self.state = State::Waiting(Sleeper::new(delay).with_tracer(self.tracer.clone()));
+  }
+
+  /// Pass processor errors through, and treat all others as a lost
+  /// connection
+  fn check<T>(&mut self, result: Result<T, AdapterError<C::Stream, C::Sink, P>>)
+    -> Result<Option<T>, P::Error>
+  {
+    match result {
+      Ok(t) => Ok(Some(t)),
+      Err(Error::Processor(err)) => Err(err),
+      Err(Error::Stream(_)) | Err(Error::Sink(_)) => {
+        self.tracer.emit("reconnect", TraceEvent::Disconnected);
+        self.disconnected();
+        Ok(None)
+      }
+    }
+  }
+}
+
+impl<C, P, H> ExtendedStream for Reconnecting<C, P, H>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+        H: Handler<P::Input, P::Output>,
+{
+  type Item = Event<P::Item>;
+  type Error = P::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    loop {
+      extended_try_ready!(self.poll_connected(task_handle));
+
+      if self.reconnected {
+        self.reconnected = false;
+        return Ok(ExtendedAsync::Ready(Some(Event::Reconnected)));
+      }
+
+      let result = self.adapter.as_mut().unwrap().extended_poll(task_handle);
+      match self.check(result)? {
+        Some(ExtendedAsync::Ready(Some(item))) => return Ok(ExtendedAsync::Ready(Some(Event::Item(item)))),
+        Some(ExtendedAsync::NotReady(agreement_to_notify)) => return Ok(ExtendedAsync::NotReady(agreement_to_notify)),
+        Some(ExtendedAsync::Ready(None)) => {
+          self.tracer.emit("reconnect", TraceEvent::Disconnected);
+          self.disconnected();
+        }
+        None => {}
+      }
+    }
+  }
+}
+
+impl<C, P, H> Stream for Reconnecting<C, P, H>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+        H: Handler<P::Input, P::Output>,
+{
+  type Item = Event<P::Item>;
+  type Error = P::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<C, P, H> ExtendedSink for Reconnecting<C, P, H>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+        H: Handler<P::Input, P::Output>,
+{
+  type SinkItem = P::SinkItem;
+  type SinkError = P::Error;
+
+  /// Items sent while the connection is failing are lost, in the same way
+  /// that they would be if they had been sent just before it failed
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.poll_connected(task_handle)? {
+      return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+    }
+
+    let result = self.adapter.as_mut().unwrap().extended_start_send(task_handle, item);
+    Ok(self.check(result)?.unwrap_or(ExtendedAsyncSink::Ready))
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    loop {
+      extended_try_ready!(self.poll_connected(task_handle));
+      let result = self.adapter.as_mut().unwrap().extended_poll_complete(task_handle);
+      if let Some(poll) = self.check(result)? {
+        return Ok(poll);
+      }
+    }
+  }
+
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    if let State::Connected = self.state {
+      let result = self.adapter.as_mut().unwrap().extended_close(task_handle);
+      if let Some(poll) = self.check(result)? {
+        return Ok(poll);
+      }
+    }
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<C, P, H> Sink for Reconnecting<C, P, H>
+  where C: Connect,
+        C::Stream: ExtendedStream<Item=P::Input>,
+        C::Sink: ExtendedSink<SinkItem=P::Output>,
+        P: Processor,
+        P::Output: Clone,
+        H: Handler<P::Input, P::Output>,
+{
+  type SinkItem = P::SinkItem;
+  type SinkError = P::Error;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::adapter::Action;
+  use extended::codec::{Framed, LinesCodec};
+  use extended::split::{split, SplitSink, SplitStream};
+  use futures::stream;
+  use std::net::SocketAddr;
+  use tokio_core::net::{TcpListener, TcpStream, TcpStreamNew};
+  use tokio_core::reactor::Handle;
+
+  struct Lines;
+
+  impl Processor for Lines {
+    type Input = String;
+    type Output = String;
+    type Item = String;
+    type SinkItem = String;
+    type Error = Void;
+
+    fn incoming(&mut self, line: String) -> Result<Action<String, String>, Void> {
+      Ok(Action::Emit(line))
+    }
+
+    fn outgoing(&mut self, line: String) -> Result<String, Void> {
+      Ok(line)
+    }
+  }
+
+  type Connection = Framed<TcpStream, LinesCodec>;
+
+  /// Connects to a local server, failing on the first attempt
+  struct Tcp {
+    address:    SocketAddr,
+    handle:     Handle,
+    attempts:   usize,
+    connecting: Option<TcpStreamNew>,
+  }
+
+  impl Connect for Tcp {
+    type Stream = SplitStream<Connection>;
+    type Sink = SplitSink<Connection>;
+    type Error = io::Error;
+
+    fn poll_connect(&mut self, task_handle: &mut TaskHandle)
+      -> ExtendedPoll<(Self::Stream, Self::Sink), Self::Error>
+    {
+      if self.connecting.is_none() {
+        self.attempts += 1;
+        if self.attempts == 1 {
+          return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "first attempt always fails"));
+        }
+        self.connecting = Some(TcpStream::connect(&self.address, &self.handle));
+      }
+
+      let stream = match self.connecting.as_mut().unwrap().poll() {
+        Ok(Async::Ready(stream)) => stream,
+        Ok(Async::NotReady) => {
+          // the reactor notifies the current task once the connection is made
+          let (_task, agreement_to_notify) = task_handle.i_will_notify();
+          return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+        }
+        Err(err) => {
+          self.connecting = None;
+          return Err(err);
+        }
+      };
+      self.connecting = None;
+
+      let (sink, stream) = split(Framed::new(stream, LinesCodec::new()));
+      Ok(ExtendedAsync::Ready((stream, sink)))
+    }
+  }
+
+  #[test]
+  fn reconnects_and_replays_subscriptions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // a server which acknowledges the first line on each connection, sends
+    // two more lines, and then drops the connection
+    let server_handle = handle.clone();
+    handle.spawn(
+      listener.incoming().for_each(move |(stream, _)| {
+        let framed = Framed::new(stream, LinesCodec::new());
+        server_handle.spawn(
+          framed.into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(subscription, framed)| {
+              let lines = vec![
+                format!("ack {}", subscription.unwrap()),
+                "tick 1".to_string(),
+                "tick 2".to_string(),
+              ];
+              framed.send_all(stream::iter_ok::<_, io::Error>(lines))
+            })
+            .map(|_| ())
+            .map_err(|err| panic!("server failed: {}", err))
+        );
+        Ok(())
+      }).map_err(|err| panic!("accept failed: {}", err))
+    );
+
+    let tcp = Tcp{address, handle: handle.clone(), attempts: 0, connecting: None};
+    let reconnecting = Reconnecting::new(tcp, Lines)
+      .with_subscriptions(vec!["subscribe trades".to_string()])
+      .with_backoff(Backoff::new(Duration::from_millis(10), Duration::from_millis(50)));
+
+    let events = core.run(reconnecting.take(8).collect()).unwrap();
+    let item = |line: &str| Event::Item(line.to_string());
+    assert_eq!(events, vec![
+      item("ack subscribe trades"),
+      item("tick 1"),
+      item("tick 2"),
+      Event::Reconnected,
+      item("ack subscribe trades"),
+      item("tick 1"),
+      item("tick 2"),
+      Event::Reconnected,
+    ]);
+  }
+
+  #[test]
+  fn backoff_grows_to_max_with_jitter() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+    let bounds = [100, 200, 400, 800, 1000, 1000];
+    for &bound in &bounds {
+      let delay = backoff.next_delay();
+      assert!(delay <= Duration::from_millis(bound), "{:?} > {}ms", delay, bound);
+      assert!(delay >= Duration::from_millis(bound / 2), "{:?} < {}ms", delay, bound / 2);
+    }
+    backoff.reset();
+    assert!(backoff.next_delay() <= Duration::from_millis(100));
+  }
+
+  #[test]
+  fn seeded_backoff_is_deterministic() {
+    let delays = |seed| {
+      let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_seed(seed);
+      (0..6).map(|_| backoff.next_delay()).collect::<Vec<Duration>>()
+    };
+    assert_eq!(delays(3), delays(3));
+    assert_ne!(delays(3), delays(4));
+  }
+}
@@ -0,0 +1,92 @@
+use common::*;
+use extended::common::*;
+use recording::{Recording, Schedule};
+
+/// A Sink that records every item it receives along with its arrival time,
+/// and which can refuse items with `NotReady` on a schedule
+pub struct Recorder<T> {
+  recording: Recording<T>,
+  schedule:  Schedule,
+}
+
+impl<T> Recorder<T> {
+  pub fn new() -> Recorder<T> {
+    Recorder{recording: Recording::new(), schedule: Schedule::never()}
+  }
+
+  /// Refuse send attempts according to `schedule`
+  pub fn with_schedule(self, schedule: Schedule) -> Recorder<T> {
+    Recorder{schedule, ..self}
+  }
+
+  /// A handle to the items received so far
+  pub fn recording(&self) -> Recording<T> {
+    self.recording.clone()
+  }
+}
+
+impl<T> ExtendedSink for Recorder<T> {
+  type SinkItem = T;
+  type SinkError = Void;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if self.schedule.refuse() {
+      // we're ready again immediately, so notify right away
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      task.notify();
+      return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+    }
+    self.recording.record(item);
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, _task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<T> Sink for Recorder<T> {
+  type SinkItem = T;
+  type SinkError = Void;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_items_in_order() {
+    let recorder = Recorder::new().with_schedule(Schedule::new(vec![true, false, false]));
+    let recording = recorder.recording();
+    let producer = extended::instant_series::Producer::from_items(0..10);
+    let _ = producer.forward(recorder).wait().unwrap();
+    recording.assert_received_in_order(&(0..10).collect::<Vec<u32>>());
+  }
+
+  #[test]
+  fn records_arrival_times() {
+    let mut core = Core::new().unwrap();
+    let recorder = Recorder::new();
+    let recording = recorder.recording();
+    let producer = extended::delayed_series::Producer::from_items(vec!['a', 'b', 'c']);
+    let _ = core.run(producer.forward(recorder)).unwrap();
+    recording.assert_received_in_order(&['a', 'b', 'c']);
+    recording.assert_inter_arrival_at_least(Duration::new(0, 900_000_000));
+  }
+}
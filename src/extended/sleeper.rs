@@ -1,14 +1,21 @@
 use common::*;
 use extended::common::*;
+use extended::trace::{Event, Tracer};
 
 /// A future which resolves after a given duration
 pub struct Sleeper {
-  until: Instant,
+  until:  Instant,
+  tracer: Tracer,
 }
 
 impl Sleeper {
   pub fn new(duration: Duration) -> Sleeper {
-    Sleeper{until: Instant::now() + duration}
+    Sleeper{until: Instant::now() + duration, tracer: Tracer::none()}
+  }
+
+  /// Report when the sleeper is armed and when it fires to `tracer`
+  pub fn with_tracer(self, tracer: Tracer) -> Sleeper {
+    Sleeper{tracer, ..self}
   }
 }
 
@@ -19,9 +26,11 @@ impl ExtendedFuture for Sleeper {
   fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
     let now = Instant::now();
     if now >= self.until {
+      self.tracer.emit("sleeper", Event::TimerFired);
       Ok(ExtendedAsync::Ready(()))
     } else {
       let remaining = self.until - now;
+      self.tracer.emit("sleeper", Event::TimerArmed{remaining});
       let (task, agreement_to_notify) = task_handle.i_will_notify();
       thread::spawn(move || {
         thread::sleep(remaining);
@@ -65,4 +74,16 @@ mod tests {
     assert!(elapsed < Duration::new(1, 200_000_000));
     assert!(elapsed > Duration::new(0, 800_000_000));
   }
+
+  #[test]
+  fn traced() {
+    let (tracer, events) = extended::trace::recording();
+    Sleeper::new(Duration::from_millis(10)).with_tracer(tracer).wait().unwrap();
+    let events = events.lock().unwrap();
+    match events.first() {
+      Some(&("sleeper", Event::TimerArmed{remaining})) => assert!(remaining <= Duration::from_millis(10)),
+      other => panic!("expected timer to be armed, got {:?}", other),
+    }
+    assert_eq!(events.last(), Some(&("sleeper", Event::TimerFired)));
+  }
 }
@@ -0,0 +1,137 @@
+use common::*;
+use extended::common::*;
+
+/// Split a `Sink + Stream` into separately owned sink and stream halves
+///
+/// The halves share the underlying object behind a mutex which is only held
+/// for the duration of a single poll.
+pub fn split<T>(duplex: T) -> (SplitSink<T>, SplitStream<T>) {
+  let inner = Arc::new(Mutex::new(duplex));
+  (SplitSink{inner: inner.clone()}, SplitStream{inner})
+}
+
+/// The stream half of a split `Sink + Stream`
+pub struct SplitStream<T> {
+  inner: Arc<Mutex<T>>,
+}
+
+impl<T: ExtendedStream> ExtendedStream for SplitStream<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    self.inner.lock().unwrap().extended_poll(task_handle)
+  }
+}
+
+impl<T: ExtendedStream> Stream for SplitStream<T> {
+  type Item = T::Item;
+  type Error = T::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+/// The sink half of a split `Sink + Stream`
+pub struct SplitSink<T> {
+  inner: Arc<Mutex<T>>,
+}
+
+impl<T: ExtendedSink> ExtendedSink for SplitSink<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = T::SinkError;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    self.inner.lock().unwrap().extended_start_send(task_handle, item)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.inner.lock().unwrap().extended_poll_complete(task_handle)
+  }
+
+  fn extended_close(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.inner.lock().unwrap().extended_close(task_handle)
+  }
+}
+
+impl<T: ExtendedSink> Sink for SplitSink<T> {
+  type SinkItem = T::SinkItem;
+  type SinkError = T::SinkError;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::codec::{Framed, LinesCodec};
+  use extended::pipe::duplex;
+  use extended::sleeper::Sleeper;
+  use futures::future::Either;
+  use futures::stream;
+
+  #[test]
+  fn halves_share_the_underlying_object() {
+    let (a, b) = duplex(64);
+    let (a_sink, a_stream) = split(Framed::new(a, LinesCodec::new()));
+    let (b_sink, b_stream) = split(Framed::new(b, LinesCodec::new()));
+
+    let thread = thread::spawn(move || {
+      let lines = b_stream.take(2).collect().wait().unwrap();
+      let _ = b_sink.send_all(stream::iter_ok::<_, io::Error>(lines)).wait().unwrap();
+    });
+
+    let lines = vec!["hello".to_string(), "world".to_string()];
+    let a_sink = a_sink.send_all(stream::iter_ok::<_, io::Error>(lines.clone())).wait().unwrap().0;
+    assert_eq!(a_stream.take(2).collect().wait().unwrap(), lines);
+    drop(a_sink);
+    thread.join().unwrap();
+  }
+
+  #[test]
+  fn halves_driven_by_different_tasks_are_both_woken() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    // a small pipe, so that both halves repeatedly wait on the other end
+    let (a, b) = duplex(8);
+    let (a_sink, a_stream) = split(Framed::new(a, LinesCodec::new()));
+    let (b_sink, b_stream) = split(Framed::new(b, LinesCodec::new()));
+    let lines = (0..100).map(|i| format!("line {}", i)).collect::<Vec<String>>();
+
+    handle.spawn(
+      b_stream.forward(b_sink)
+        .map(|_| ())
+        .map_err(|err| panic!("echo failed: {}", err))
+    );
+    handle.spawn(
+      a_sink.send_all(stream::iter_ok::<_, io::Error>(lines.clone()))
+        .map(|_| ())
+        .map_err(|err| panic!("writer failed: {}", err))
+    );
+
+    let reader = a_stream.take(lines.len() as u64).collect();
+    match core.run(reader.select2(Sleeper::new(Duration::from_secs(5)))) {
+      Ok(Either::A((received, _))) => assert_eq!(received, lines),
+      _ => panic!("a half was never woken"),
+    }
+  }
+}
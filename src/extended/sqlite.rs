@@ -0,0 +1,194 @@
+use common::*;
+use extended::common::*;
+
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+struct State {
+  /// a batch has been sent to the worker and has not been committed yet
+  busy:  bool,
+  /// the last write failed
+  error: Option<rusqlite::Error>,
+  /// rows committed so far
+  rows:  u64,
+  /// the task waiting for the current write to finish
+  task:  Option<Task>,
+}
+
+/// A sink which writes each batch of items it receives to SQLite in a
+/// single transaction, on a worker thread, for use behind a `BatchSink`
+///
+/// Each item is inserted by executing `sql` with the parameters returned by
+/// the `params` function. Only one batch is written at a time: while a
+/// write is in flight `start_send` returns `NotReady`, and `poll_complete`
+/// is ready once it has been committed.
+pub struct SqliteWriter<T> {
+  sender: Option<mpsc::Sender<Vec<T>>>,
+  worker: Option<JoinHandle<Connection>>,
+  state:  Arc<Mutex<State>>,
+}
+
+impl<T: Send + 'static> SqliteWriter<T> {
+  pub fn new<F>(mut connection: Connection, sql: &str, params: F) -> SqliteWriter<T>
+    where F: Fn(&T) -> Vec<Value> + Send + 'static
+  {
+    let state = Arc::new(Mutex::new(State{busy: false, error: None, rows: 0, task: None}));
+    let (sender, receiver) = mpsc::channel::<Vec<T>>();
+    let sql = sql.to_string();
+
+    let shared = state.clone();
+    let worker = thread::spawn(move || {
+      for batch in receiver {
+        let result = write(&mut connection, &sql, &params, &batch);
+        let task = {
+          let mut state = shared.lock().unwrap();
+          state.busy = false;
+          match result {
+            Ok(()) => state.rows += batch.len() as u64,
+            Err(error) => state.error = Some(error),
+          }
+          state.task.take()
+        };
+        if let Some(task) = task {
+          task.notify();
+        }
+      }
+      connection
+    });
+
+    SqliteWriter{sender: Some(sender), worker: Some(worker), state}
+  }
+
+  /// The number of rows committed so far
+  pub fn rows(&self) -> u64 {
+    self.state.lock().unwrap().rows
+  }
+
+  /// Wait for any write in flight, and return the connection
+  pub fn into_connection(mut self) -> Connection {
+    self.sender.take();
+    self.worker.take().unwrap().join().expect("sqlite worker panicked")
+  }
+
+  /// Fail with the error from the last write, if there was one, or return
+  /// `NotReady` if a write is still in flight
+  fn poll_idle(&mut self, task_handle: &mut TaskHandle) -> Result<ExtendedAsync<()>, rusqlite::Error> {
+    let mut state = self.state.lock().unwrap();
+    if let Some(error) = state.error.take() {
+      return Err(error);
+    }
+    if state.busy {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.task = Some(task);
+      return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+    }
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+fn write<T, F>(connection: &mut Connection, sql: &str, params: &F, batch: &[T]) -> Result<(), rusqlite::Error>
+  where F: Fn(&T) -> Vec<Value>
+{
+  let transaction = connection.transaction()?;
+  {
+    let mut statement = transaction.prepare_cached(sql)?;
+    for item in batch {
+      statement.execute(params_from_iter(params(item)))?;
+    }
+  }
+  transaction.commit()
+}
+
+impl<T> Drop for SqliteWriter<T> {
+  fn drop(&mut self) {
+    self.sender.take();
+    if let Some(worker) = self.worker.take() {
+      let _ = worker.join();
+    }
+  }
+}
+
+impl<T: Send + 'static> ExtendedSink for SqliteWriter<T> {
+  type SinkItem = Vec<T>;
+  type SinkError = rusqlite::Error;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, batch: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.poll_idle(task_handle)? {
+      return Ok(ExtendedAsyncSink::NotReady(batch, agreement_to_notify));
+    }
+    self.state.lock().unwrap().busy = true;
+    self.sender.as_ref().unwrap().send(batch).expect("sqlite worker exited");
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.poll_idle(task_handle)
+  }
+}
+
+impl<T: Send + 'static> Sink for SqliteWriter<T> {
+  type SinkItem = Vec<T>;
+  type SinkError = rusqlite::Error;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+
+  fn close(&mut self) -> Poll<(), Self::SinkError> {
+    sink_close_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::batch::BatchSink;
+  use extended::instant_series::Producer;
+
+  fn writer() -> SqliteWriter<u8> {
+    let connection = Connection::open_in_memory().unwrap();
+    connection.execute("CREATE TABLE items (value INTEGER NOT NULL)", []).unwrap();
+    SqliteWriter::new(connection, "INSERT INTO items (value) VALUES (?1)", |item: &u8| vec![Value::Integer(*item as i64)])
+  }
+
+  #[test]
+  fn batches_are_committed() {
+    let sink = BatchSink::new(writer(), 10, Duration::from_millis(50));
+
+    let (_, sink) = Producer::from_items(0..25).map_err(|v| -> rusqlite::Error { match v {} }).forward(sink).wait().unwrap();
+
+    let writer = sink.into_inner();
+    assert_eq!(writer.rows(), 25);
+    let connection = writer.into_connection();
+    let mut statement = connection.prepare("SELECT value FROM items ORDER BY rowid").unwrap();
+    let values = statement.query_map([], |row| row.get::<_, u8>(0)).unwrap()
+      .collect::<Result<Vec<u8>, _>>().unwrap();
+    assert_eq!(values, (0..25).collect::<Vec<u8>>());
+  }
+
+  #[test]
+  fn errors_reach_the_sink() {
+    let connection = Connection::open_in_memory().unwrap();
+    let sink = SqliteWriter::new(connection, "INSERT INTO missing (value) VALUES (?1)", |item: &u8| vec![Value::Integer(*item as i64)]);
+    let sink = BatchSink::new(sink, 2, Duration::from_millis(50));
+
+    let error = match Producer::from_items(0..4).map_err(|v| -> rusqlite::Error { match v {} }).forward(sink).wait() {
+      Ok(_) => panic!("writing to a missing table succeeded"),
+      Err(error) => error,
+    };
+    match error {
+      rusqlite::Error::SqliteFailure(_, Some(ref message)) if message == "no such table: missing" => {}
+      error => panic!("unexpected error: {:?}", error),
+    }
+  }
+}
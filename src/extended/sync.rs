@@ -0,0 +1,417 @@
+use common::*;
+use extended::common::*;
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+
+struct State {
+  permits: usize,
+  next_id: usize,
+  /// pending acquires, in the order that they arrived
+  waiting: VecDeque<(usize, Task)>,
+  /// pending acquires to which a permit has been handed, but which have not
+  /// yet been polled to collect it
+  granted: HashSet<usize>,
+}
+
+impl State {
+  /// Give a permit to the longest waiting acquire, or return it to the pool
+  /// if nobody is waiting
+  fn release(&mut self) -> Option<Task> {
+    match self.waiting.pop_front() {
+      Some((id, task)) => {
+        self.granted.insert(id);
+        Some(task)
+      }
+      None => {
+        self.permits += 1;
+        None
+      }
+    }
+  }
+}
+
+fn release(state: &::std::sync::Mutex<State>) {
+  let task = state.lock().unwrap().release();
+  if let Some(task) = task {
+    task.notify();
+  }
+}
+
+/// A fair counting semaphore, which hands out permits in the order that they
+/// were requested
+///
+/// Cloning a `Semaphore` produces another handle to the same set of permits.
+#[derive(Clone)]
+pub struct Semaphore {
+  state: Arc<::std::sync::Mutex<State>>,
+}
+
+impl Semaphore {
+  pub fn new(permits: usize) -> Semaphore {
+    Semaphore {
+      state: Arc::new(::std::sync::Mutex::new(State {
+        permits,
+        next_id: 0,
+        waiting: VecDeque::new(),
+        granted: HashSet::new(),
+      })),
+    }
+  }
+
+  /// Returns a future which resolves to a permit once one is available and
+  /// all earlier acquires have been satisfied
+  pub fn acquire(&self) -> Acquire {
+    Acquire{state: self.state.clone(), id: None}
+  }
+
+  /// Get a permit without waiting, if one is available and nobody else is
+  /// waiting for one
+  pub fn try_acquire(&self) -> Option<Permit> {
+    let mut state = self.state.lock().unwrap();
+    if state.waiting.is_empty() && state.permits > 0 {
+      state.permits -= 1;
+      Some(Permit{state: self.state.clone()})
+    } else {
+      None
+    }
+  }
+
+  pub fn available_permits(&self) -> usize {
+    self.state.lock().unwrap().permits
+  }
+}
+
+/// A future which resolves to a `Permit`
+///
+/// Dropping an `Acquire` which is waiting removes it from the queue, and if a
+/// permit had already been handed to it, passes that permit on.
+pub struct Acquire {
+  state: Arc<::std::sync::Mutex<State>>,
+  id:    Option<usize>,
+}
+
+impl ExtendedFuture for Acquire {
+  type Item = Permit;
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    let acquired = match self.id {
+      Some(id) => state.granted.remove(&id),
+      None => if state.waiting.is_empty() && state.permits > 0 {
+        state.permits -= 1;
+        true
+      } else {
+        false
+      },
+    };
+
+    if acquired {
+      self.id = None;
+      return Ok(ExtendedAsync::Ready(Permit{state: self.state.clone()}));
+    }
+
+    let (task, agreement_to_notify) = task_handle.i_will_notify();
+    match self.id {
+      Some(id) => {
+        let entry = state.waiting.iter_mut().find(|&&mut (waiting, _)| waiting == id).unwrap();
+        entry.1 = task;
+      }
+      None => {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back((id, task));
+        self.id = Some(id);
+      }
+    }
+    Ok(ExtendedAsync::NotReady(agreement_to_notify))
+  }
+}
+
+impl Future for Acquire {
+  type Item = Permit;
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+impl Drop for Acquire {
+  fn drop(&mut self) {
+    if let Some(id) = self.id {
+      let task = {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.waiting.iter().position(|&(waiting, _)| waiting == id) {
+          state.waiting.remove(index);
+          None
+        } else if state.granted.remove(&id) {
+          state.release()
+        } else {
+          None
+        }
+      };
+
+      if let Some(task) = task {
+        task.notify();
+      }
+    }
+  }
+}
+
+/// A permit from a `Semaphore`, which is released when dropped
+pub struct Permit {
+  state: Arc<::std::sync::Mutex<State>>,
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    release(&self.state);
+  }
+}
+
+struct Shared<T> {
+  semaphore: Semaphore,
+  value:     UnsafeCell<T>,
+}
+
+// access to `value` is serialized by the semaphore
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// An asynchronous mutex, built on a `Semaphore` with a single permit, whose
+/// lock is handed out in the order it was requested
+///
+/// Cloning a `Mutex` produces another handle to the same value.
+pub struct Mutex<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T> Mutex<T> {
+  pub fn new(value: T) -> Mutex<T> {
+    Mutex {
+      shared: Arc::new(Shared {
+        semaphore: Semaphore::new(1),
+        value:     UnsafeCell::new(value),
+      }),
+    }
+  }
+
+  /// Returns a future which resolves to a guard once the lock is acquired
+  pub fn lock(&self) -> Lock<T> {
+    Lock{shared: self.shared.clone(), acquire: self.shared.semaphore.acquire()}
+  }
+
+  pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+    self.shared.semaphore.try_acquire()
+      .map(|permit| MutexGuard{shared: self.shared.clone(), _permit: permit})
+  }
+}
+
+impl<T> Clone for Mutex<T> {
+  fn clone(&self) -> Mutex<T> {
+    Mutex{shared: self.shared.clone()}
+  }
+}
+
+/// A future which resolves to a `MutexGuard`
+pub struct Lock<T> {
+  shared:  Arc<Shared<T>>,
+  acquire: Acquire,
+}
+
+impl<T> ExtendedFuture for Lock<T> {
+  type Item = MutexGuard<T>;
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let permit = extended_try_ready!(self.acquire.extended_poll(task_handle));
+    Ok(ExtendedAsync::Ready(MutexGuard{shared: self.shared.clone(), _permit: permit}))
+  }
+}
+
+impl<T> Future for Lock<T> {
+  type Item = MutexGuard<T>;
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+/// Exclusive access to the value in a `Mutex`, which is unlocked when dropped
+///
+/// A guard hands out `&T`, so like `std::sync::MutexGuard` it can only be
+/// shared between threads if `T` can:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<tokio_by_hand::extended::sync::MutexGuard<std::cell::Cell<u8>>>();
+/// ```
+pub struct MutexGuard<T> {
+  shared:  Arc<Shared<T>>,
+  _permit: Permit,
+}
+
+// `Shared<T>` is `Sync` whenever `T` is `Send`, which is enough for the
+// mutex itself, but a shared guard gives out `&T` to every thread
+unsafe impl<T: Sync> Sync for MutexGuard<T> {}
+
+impl<T> Deref for MutexGuard<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.shared.value.get() }
+  }
+}
+
+impl<T> DerefMut for MutexGuard<T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.shared.value.get() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+
+  fn assert_sync<T: Sync>() {}
+
+  fn ready<T>(poll: Poll<T, Void>) -> Option<T> {
+    match poll {
+      Ok(Async::Ready(t)) => Some(t),
+      Ok(Async::NotReady) => None,
+      Err(err) => match err {},
+    }
+  }
+
+  #[test]
+  fn permits_limit_holders() {
+    let semaphore = Semaphore::new(2);
+    let first = semaphore.try_acquire().unwrap();
+    let _second = semaphore.try_acquire().unwrap();
+    assert!(semaphore.try_acquire().is_none());
+    drop(first);
+    assert!(semaphore.try_acquire().is_some());
+  }
+
+  #[test]
+  fn permits_are_handed_out_in_order() {
+    let semaphore = Semaphore::new(1);
+
+    future::lazy(|| {
+      let held = semaphore.try_acquire().unwrap();
+      let mut first = semaphore.acquire();
+      let mut second = semaphore.acquire();
+      let mut third = semaphore.acquire();
+
+      assert!(ready(third.poll()).is_none());
+      assert!(ready(first.poll()).is_none());
+      assert!(ready(second.poll()).is_none());
+
+      drop(held);
+      assert!(semaphore.try_acquire().is_none());
+      assert!(ready(first.poll()).is_none());
+      assert!(ready(second.poll()).is_none());
+      let held = ready(third.poll()).unwrap();
+
+      drop(held);
+      assert!(ready(second.poll()).is_none());
+      let held = ready(first.poll()).unwrap();
+
+      drop(held);
+      ready(second.poll()).unwrap();
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn dropped_acquire_leaves_queue() {
+    let semaphore = Semaphore::new(1);
+
+    future::lazy(|| {
+      let held = semaphore.try_acquire().unwrap();
+      let mut first = semaphore.acquire();
+      let mut second = semaphore.acquire();
+      assert!(ready(first.poll()).is_none());
+      assert!(ready(second.poll()).is_none());
+
+      drop(first);
+      drop(held);
+      ready(second.poll()).unwrap();
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn dropped_acquire_passes_on_granted_permit() {
+    let semaphore = Semaphore::new(1);
+
+    future::lazy(|| {
+      let held = semaphore.try_acquire().unwrap();
+      let mut first = semaphore.acquire();
+      let mut second = semaphore.acquire();
+      assert!(ready(first.poll()).is_none());
+      assert!(ready(second.poll()).is_none());
+
+      drop(held);
+      drop(first);
+      ready(second.poll()).unwrap();
+
+      drop(second);
+      assert_eq!(semaphore.available_permits(), 1);
+
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn mutex_serializes_tasks() {
+    let mut core = Core::new().unwrap();
+    let mutex = Mutex::new(0u64);
+
+    let increments = (0..10).map(|_| {
+      mutex.lock().and_then(|mut guard| {
+        let value = *guard;
+        // hold the lock across a suspension point
+        extended::sleeper::Sleeper::new(Duration::new(0, 10_000_000))
+          .map(move |()| *guard = value + 1)
+      })
+    }).collect::<Vec<_>>();
+
+    core.run(future::join_all(increments)).unwrap();
+    assert_eq!(*mutex.try_lock().unwrap(), 10);
+  }
+
+  #[test]
+  fn mutex_across_threads() {
+    let mutex = Mutex::new(Vec::new());
+    let threads = (0..8).map(|i| {
+      let mutex = mutex.clone();
+      thread::spawn(move || {
+        for j in 0..100 {
+          mutex.lock().wait().unwrap().push(i * 100 + j);
+        }
+      })
+    }).collect::<Vec<thread::JoinHandle<()>>>();
+
+    for thread in threads {
+      thread.join().unwrap();
+    }
+
+    let mut values = mutex.try_lock().unwrap().clone();
+    values.sort();
+    assert_eq!(values, (0..800).collect::<Vec<u32>>());
+  }
+
+  #[test]
+  fn guard_is_sync_when_value_is() {
+    assert_sync::<MutexGuard<u8>>();
+    assert_sync::<Mutex<::std::cell::Cell<u8>>>();
+  }
+}
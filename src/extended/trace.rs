@@ -0,0 +1,114 @@
+use common::*;
+
+/// Something that happened inside an adapter, sink, or timer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+  /// An item was sent to an underlying sink
+  Sent,
+  /// An item was received from an underlying stream
+  Received,
+  /// An item was buffered, leaving `len` items in the buffer
+  Buffered{len: usize},
+  /// The buffer was emptied into the underlying sink
+  Flushed,
+  /// Items still waiting to be sent were thrown away, for example because
+  /// the connection they were meant for has gone
+  Discarded{count: usize},
+  /// The owner of a buffer was dropped with `outstanding` items unaccounted
+  /// for
+  Dropped{outstanding: u64},
+  /// A malformed item was skipped
+  Skipped{reason: String},
+  /// A timer will fire after `remaining`
+  TimerArmed{remaining: Duration},
+  /// A timer fired
+  TimerFired,
+  /// A connection attempt failed
+  ConnectFailed{reason: String},
+  /// A connection failed or was closed by the other side
+  Disconnected,
+  /// A new connection will be attempted after `delay`
+  Reconnecting{delay: Duration},
+}
+
+/// A destination for events
+pub trait Trace: Send + Sync {
+  /// Handle `event`, which happened in the component named `source`
+  fn event(&self, source: &'static str, event: &Event);
+}
+
+impl<F: Fn(&'static str, &Event) + Send + Sync> Trace for F {
+  fn event(&self, source: &'static str, event: &Event) {
+    self(source, event)
+  }
+}
+
+/// Writes every event to standard error
+#[derive(Debug, Clone, Copy)]
+pub struct Stderr;
+
+impl Trace for Stderr {
+  fn event(&self, source: &'static str, event: &Event) {
+    eprintln!("{}: {:?}", source, event);
+  }
+}
+
+/// A cheaply cloneable handle to an optional `Trace`, which discards events
+/// unless one has been set
+#[derive(Clone, Default)]
+pub struct Tracer {
+  inner: Option<Arc<dyn Trace>>,
+}
+
+impl Tracer {
+  /// A tracer which discards all events
+  pub fn none() -> Tracer {
+    Tracer{inner: None}
+  }
+
+  pub fn new<T: Trace + 'static>(trace: T) -> Tracer {
+    Tracer{inner: Some(Arc::new(trace))}
+  }
+
+  pub fn emit(&self, source: &'static str, event: Event) {
+    if let Some(ref trace) = self.inner {
+      trace.event(source, &event);
+    }
+  }
+}
+
+impl fmt::Debug for Tracer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "Tracer({})", if self.inner.is_some() { "on" } else { "off" })
+  }
+}
+
+/// Events recorded by a tracer made with `recording`
+#[cfg(test)]
+pub type Recording = Arc<Mutex<Vec<(&'static str, Event)>>>;
+
+/// A tracer which records everything it is sent, along with a handle to the
+/// recording, for use in tests
+#[cfg(test)]
+pub fn recording() -> (Tracer, Recording) {
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let recorded = events.clone();
+  let tracer = Tracer::new(move |source: &'static str, event: &Event| {
+    recorded.lock().unwrap().push((source, event.clone()));
+  });
+  (tracer, events)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn events_reach_the_trace() {
+    let (tracer, events) = recording();
+    tracer.clone().emit("test", Event::Flushed);
+    tracer.emit("test", Event::Buffered{len: 1});
+    Tracer::none().emit("test", Event::Sent);
+    assert_eq!(*events.lock().unwrap(), vec![("test", Event::Flushed), ("test", Event::Buffered{len: 1})]);
+  }
+}
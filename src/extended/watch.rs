@@ -0,0 +1,231 @@
+use common::*;
+use extended::common::*;
+
+struct State<T> {
+  value:       T,
+  version:     u64,
+  sender_gone: bool,
+  next_id:     usize,
+  waiting:     HashMap<usize, Task>,
+}
+
+impl<T> State<T> {
+  fn register(&mut self) -> usize {
+    let id = self.next_id;
+    self.next_id += 1;
+    id
+  }
+}
+
+/// Create a channel which holds a single value, starting with `initial`, and
+/// notifies receivers whenever it changes
+pub fn channel<T: Clone>(initial: T) -> (Sender<T>, Receiver<T>) {
+  let state = Arc::new(Mutex::new(State {
+    value:       initial,
+    version:     1,
+    sender_gone: false,
+    next_id:     1,
+    waiting:     HashMap::new(),
+  }));
+
+  (Sender{state: state.clone()}, Receiver{state, id: 0, seen: 0})
+}
+
+/// The sending half of a watch channel
+pub struct Sender<T> {
+  state: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+  /// Replace the current value, and notify all receivers
+  pub fn broadcast(&self, value: T) {
+    let tasks = {
+      let mut state = self.state.lock().unwrap();
+      state.value = value;
+      state.version += 1;
+      state.waiting.drain().map(|(_, task)| task).collect::<Vec<Task>>()
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+
+  /// Create a new receiver, which will first yield the current value
+  pub fn subscribe(&self) -> Receiver<T> {
+    let id = self.state.lock().unwrap().register();
+    Receiver{state: self.state.clone(), id, seen: 0}
+  }
+}
+
+impl<T: Clone> ExtendedSink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = Void;
+
+  fn extended_start_send(&mut self, _task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    self.broadcast(item);
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, _task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    Ok(ExtendedAsync::Ready(()))
+  }
+}
+
+impl<T: Clone> Sink for Sender<T> {
+  type SinkItem = T;
+  type SinkError = Void;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let tasks = {
+      let mut state = self.state.lock().unwrap();
+      state.sender_gone = true;
+      state.waiting.drain().map(|(_, task)| task).collect::<Vec<Task>>()
+    };
+
+    for task in tasks {
+      task.notify();
+    }
+  }
+}
+
+/// The receiving half of a watch channel, a stream which yields the current
+/// value when first polled and then the latest value after each change
+///
+/// Values which are replaced before the receiver is polled are skipped. The
+/// stream ends when the sender is dropped.
+pub struct Receiver<T> {
+  state: Arc<Mutex<State<T>>>,
+  id:    usize,
+  seen:  u64,
+}
+
+impl<T: Clone> Receiver<T> {
+  /// Get a copy of the current value, without waiting for it to change
+  pub fn get(&self) -> T {
+    self.state.lock().unwrap().value.clone()
+  }
+}
+
+impl<T> Clone for Receiver<T> {
+  fn clone(&self) -> Receiver<T> {
+    let id = self.state.lock().unwrap().register();
+    Receiver{state: self.state.clone(), id, seen: self.seen}
+  }
+}
+
+impl<T: Clone> ExtendedStream for Receiver<T> {
+  type Item = T;
+  type Error = Void;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    let mut state = self.state.lock().unwrap();
+    if state.version != self.seen {
+      self.seen = state.version;
+      Ok(ExtendedAsync::Ready(Some(state.value.clone())))
+    } else if state.sender_gone {
+      Ok(ExtendedAsync::Ready(None))
+    } else {
+      let (task, agreement_to_notify) = task_handle.i_will_notify();
+      state.waiting.insert(self.id, task);
+      Ok(ExtendedAsync::NotReady(agreement_to_notify))
+    }
+  }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+  type Item = T;
+  type Error = Void;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    self.state.lock().unwrap().waiting.remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  #[test]
+  fn receiver_starts_with_current_value() {
+    let (sender, receiver) = channel(0u8);
+    sender.broadcast(1);
+    drop(sender);
+    assert_eq!(receiver.collect().wait(), Ok(vec![1]));
+  }
+
+  #[test]
+  fn only_latest_value_is_seen() {
+    let (sender, receiver) = channel(0u8);
+    let mut receiver = receiver.wait();
+    assert_eq!(receiver.next(), Some(Ok(0)));
+    sender.broadcast(1);
+    sender.broadcast(2);
+    sender.broadcast(3);
+    assert_eq!(receiver.next(), Some(Ok(3)));
+    drop(sender);
+    assert_eq!(receiver.next(), None);
+  }
+
+  #[test]
+  fn get_does_not_wait() {
+    let (sender, receiver) = channel(0u8);
+    assert_eq!(receiver.get(), 0);
+    sender.broadcast(1);
+    assert_eq!(receiver.get(), 1);
+  }
+
+  #[test]
+  fn changes_wake_receivers_on_other_threads() {
+    let (sender, receiver) = channel(0u8);
+    let second = receiver.clone();
+    let (ready, started) = mpsc::channel();
+    let receivers = vec![receiver, second].into_iter()
+      .map(|receiver| {
+        let ready = ready.clone();
+        thread::spawn(move || {
+          let (first, rest) = match receiver.into_future().wait() {
+            Ok(next) => next,
+            Err((void, _)) => match void {},
+          };
+          // only change the value once the initial one has been seen
+          ready.send(()).unwrap();
+          first.into_iter().chain(rest.collect().wait().unwrap()).collect::<Vec<u8>>()
+        })
+      })
+      .collect::<Vec<thread::JoinHandle<Vec<u8>>>>();
+
+    for _ in 0..2 {
+      started.recv().unwrap();
+    }
+    sender.broadcast(1);
+    drop(sender);
+
+    for receiver in receivers {
+      assert_eq!(receiver.join().unwrap(), vec![0, 1]);
+    }
+  }
+}
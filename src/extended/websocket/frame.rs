@@ -0,0 +1,264 @@
+use common::*;
+use extended::codec::{ExtendedDecoder, ExtendedEncoder};
+use super::Role;
+
+/// The longest payload permitted in a control frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The default longest payload that will be accepted in a single frame
+const DEFAULT_MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+  Continuation,
+  Text,
+  Binary,
+  Close,
+  Ping,
+  Pong,
+}
+
+impl Opcode {
+  fn from_u8(byte: u8) -> Option<Opcode> {
+    match byte {
+      0x0 => Some(Opcode::Continuation),
+      0x1 => Some(Opcode::Text),
+      0x2 => Some(Opcode::Binary),
+      0x8 => Some(Opcode::Close),
+      0x9 => Some(Opcode::Ping),
+      0xA => Some(Opcode::Pong),
+      _ => None,
+    }
+  }
+
+  fn as_u8(self) -> u8 {
+    match self {
+      Opcode::Continuation => 0x0,
+      Opcode::Text => 0x1,
+      Opcode::Binary => 0x2,
+      Opcode::Close => 0x8,
+      Opcode::Ping => 0x9,
+      Opcode::Pong => 0xA,
+    }
+  }
+
+  pub fn is_control(self) -> bool {
+    match self {
+      Opcode::Close | Opcode::Ping | Opcode::Pong => true,
+      Opcode::Continuation | Opcode::Text | Opcode::Binary => false,
+    }
+  }
+}
+
+/// A single websocket frame, with its payload unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+  pub fin:     bool,
+  pub opcode:  Opcode,
+  pub payload: Vec<u8>,
+}
+
+impl Frame {
+  pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+    Frame{fin: true, opcode, payload}
+  }
+}
+
+pub fn protocol_error(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("websocket protocol error: {}", message))
+}
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+  for (i, byte) in payload.iter_mut().enumerate() {
+    *byte ^= key[i % 4];
+  }
+}
+
+/// A codec for websocket frames
+///
+/// Clients mask the frames they send and require that the frames they
+/// receive are unmasked, and servers do the opposite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+  role:        Role,
+  max_payload: usize,
+}
+
+impl FrameCodec {
+  pub fn new(role: Role) -> FrameCodec {
+    FrameCodec{role, max_payload: DEFAULT_MAX_PAYLOAD}
+  }
+}
+
+impl ExtendedDecoder for FrameCodec {
+  type Item = Frame;
+  type Error = io::Error;
+
+  fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Frame>, io::Error> {
+    if buffer.len() < 2 {
+      return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    if buffer[0] & 0x70 != 0 {
+      return Err(protocol_error("reserved bits set"));
+    }
+    let opcode = Opcode::from_u8(buffer[0] & 0x0F).ok_or_else(|| protocol_error("unknown opcode"))?;
+
+    let masked = buffer[1] & 0x80 != 0;
+    match (self.role, masked) {
+      (Role::Server, false) => return Err(protocol_error("unmasked frame from client")),
+      (Role::Client, true) => return Err(protocol_error("masked frame from server")),
+      _ => {}
+    }
+
+    let (length, mut offset) = match buffer[1] & 0x7F {
+      126 => {
+        if buffer.len() < 4 {
+          return Ok(None);
+        }
+        ((buffer[2] as usize) << 8 | buffer[3] as usize, 4)
+      }
+      127 => {
+        if buffer.len() < 10 {
+          return Ok(None);
+        }
+        let length = buffer[2..10].iter().fold(0u64, |length, &byte| length << 8 | byte as u64);
+        if length > self.max_payload as u64 {
+          return Err(protocol_error("frame too long"));
+        }
+        (length as usize, 10)
+      }
+      length => (length as usize, 2),
+    };
+
+    if length > self.max_payload {
+      return Err(protocol_error("frame too long"));
+    }
+
+    if opcode.is_control() && (length > MAX_CONTROL_PAYLOAD || !fin) {
+      return Err(protocol_error("control frames must be short and unfragmented"));
+    }
+
+    let mut key = None;
+    if masked {
+      if buffer.len() < offset + 4 {
+        return Ok(None);
+      }
+      key = Some([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]]);
+      offset += 4;
+    }
+
+    if buffer.len() < offset + length {
+      return Ok(None);
+    }
+
+    let mut payload = buffer.drain(..offset + length).skip(offset).collect::<Vec<u8>>();
+    if let Some(key) = key {
+      apply_mask(&mut payload, key);
+    }
+
+    Ok(Some(Frame{fin, opcode, payload}))
+  }
+}
+
+impl ExtendedEncoder for FrameCodec {
+  type Item = Frame;
+  type Error = io::Error;
+
+  fn encode(&mut self, mut frame: Frame, buffer: &mut Vec<u8>) -> Result<(), io::Error> {
+    let fin = if frame.fin { 0x80 } else { 0 };
+    buffer.push(fin | frame.opcode.as_u8());
+
+    let mask = match self.role {
+      Role::Client => 0x80,
+      Role::Server => 0,
+    };
+
+    let length = frame.payload.len();
+    if length < 126 {
+      buffer.push(mask | length as u8);
+    } else if length <= 0xFFFF {
+      buffer.push(mask | 126);
+      buffer.extend_from_slice(&[(length >> 8) as u8, length as u8]);
+    } else {
+      buffer.push(mask | 127);
+      let length = length as u64;
+      buffer.extend((0..8).rev().map(|i| (length >> (i * 8)) as u8));
+    }
+
+    if let Role::Client = self.role {
+      let key = random::<[u8; 4]>();
+      buffer.extend_from_slice(&key);
+      apply_mask(&mut frame.payload, key);
+    }
+
+    buffer.extend_from_slice(&frame.payload);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip(frame: Frame) {
+    let mut buffer = Vec::new();
+    FrameCodec::new(Role::Client).encode(frame.clone(), &mut buffer).unwrap();
+    // the payload is masked on the wire
+    if frame.payload.len() > 4 {
+      assert!(buffer.windows(frame.payload.len()).all(|window| window != &frame.payload[..]));
+    }
+    let decoded = FrameCodec::new(Role::Server).decode(&mut buffer).unwrap();
+    assert_eq!(decoded, Some(frame));
+    assert!(buffer.is_empty());
+  }
+
+  #[test]
+  fn round_trip_all_length_encodings() {
+    round_trip(Frame::new(Opcode::Text, b"hello".to_vec()));
+    round_trip(Frame::new(Opcode::Binary, vec![7; 126]));
+    round_trip(Frame::new(Opcode::Binary, vec![7; 70000]));
+    round_trip(Frame{fin: false, opcode: Opcode::Continuation, payload: vec![]});
+  }
+
+  #[test]
+  fn rfc_unmasked_example() {
+    let mut buffer = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    let frame = FrameCodec::new(Role::Client).decode(&mut buffer).unwrap().unwrap();
+    assert_eq!(frame, Frame::new(Opcode::Text, b"Hello".to_vec()));
+  }
+
+  #[test]
+  fn rfc_masked_example() {
+    let mut buffer = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    let frame = FrameCodec::new(Role::Server).decode(&mut buffer).unwrap().unwrap();
+    assert_eq!(frame, Frame::new(Opcode::Text, b"Hello".to_vec()));
+  }
+
+  #[test]
+  fn partial_frames_wait_for_more_input() {
+    let mut encoded = Vec::new();
+    FrameCodec::new(Role::Server).encode(Frame::new(Opcode::Binary, vec![1; 300]), &mut encoded).unwrap();
+    let mut codec = FrameCodec::new(Role::Client);
+    let mut buffer = Vec::new();
+    for byte in &encoded[..encoded.len() - 1] {
+      buffer.push(*byte);
+      assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    }
+    buffer.push(encoded[encoded.len() - 1]);
+    assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Frame::new(Opcode::Binary, vec![1; 300])));
+  }
+
+  #[test]
+  fn server_rejects_unmasked_frames() {
+    let mut buffer = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    assert!(FrameCodec::new(Role::Server).decode(&mut buffer).is_err());
+  }
+
+  #[test]
+  fn fragmented_control_frames_are_rejected() {
+    let mut buffer = vec![0x09, 0x00];
+    assert!(FrameCodec::new(Role::Client).decode(&mut buffer).is_err());
+  }
+}
@@ -0,0 +1,369 @@
+use common::*;
+use extended::common::*;
+use super::{Role, WebSocket};
+use super::frame::protocol_error;
+
+/// The GUID which is appended to the client's key when computing the
+/// server's accept key
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest HTTP request or response head that will be accepted
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+
+/// The SHA-1 digest of `data`
+fn sha1(data: &[u8]) -> [u8; 20] {
+  let mut state: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+  let mut message = data.to_vec();
+  let bit_length = (data.len() as u64) * 8;
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend((0..8).rev().map(|i| (bit_length >> (i * 8)) as u8));
+
+  for block in message.chunks(64) {
+    let mut schedule = [0u32; 80];
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+      *word = bytes.iter().fold(0, |word, &byte| word << 8 | byte as u32);
+    }
+    for i in 16..80 {
+      schedule[i] = (schedule[i - 3] ^ schedule[i - 8] ^ schedule[i - 14] ^ schedule[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = state;
+    for (i, word) in schedule.iter().enumerate() {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+        20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+        _ => (b ^ c ^ d, 0xCA62_C1D6),
+      };
+      let temp = a.rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = temp;
+    }
+
+    for (value, working) in state.iter_mut().zip(&[a, b, c, d, e]) {
+      *value = value.wrapping_add(*working);
+    }
+  }
+
+  let mut digest = [0; 20];
+  for (bytes, value) in digest.chunks_mut(4).zip(&state) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = (value >> (24 - i * 8)) as u8;
+    }
+  }
+  digest
+}
+
+/// The standard, padded base64 encoding of `data`
+fn base64(data: &[u8]) -> String {
+  const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+  let mut encoded = String::new();
+  for group in data.chunks(3) {
+    let bits = group.iter().enumerate()
+      .fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - i * 8));
+    for i in 0..4 {
+      if i <= group.len() {
+        encoded.push(ALPHABET[(bits >> (18 - i * 6) & 0x3F) as usize] as char);
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+/// The `Sec-WebSocket-Accept` value that a server must send in response to
+/// the client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+  base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// The start line and headers of an HTTP request or response
+struct Head {
+  start_line: String,
+  headers:    Vec<(String, String)>,
+}
+
+impl Head {
+  fn parse(bytes: &[u8]) -> Result<Head, io::Error> {
+    let text = ::std::str::from_utf8(bytes).map_err(|_| protocol_error("handshake was not valid UTF-8"))?;
+    let mut lines = text.split("\r\n").filter(|line| !line.is_empty());
+    let start_line = lines.next().ok_or_else(|| protocol_error("empty handshake"))?.to_string();
+
+    let mut headers = Vec::new();
+    for line in lines {
+      let colon = line.find(':').ok_or_else(|| protocol_error("malformed handshake header"))?;
+      headers.push((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()));
+    }
+
+    Ok(Head{start_line, headers})
+  }
+
+  fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter()
+      .find(|(header, _)| header == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Returns true if the comma-separated header `name` contains `token`
+  fn has_token(&self, name: &str, token: &str) -> bool {
+    self.header(name)
+      .map(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+      .unwrap_or(false)
+  }
+}
+
+/// Read from `io` into `buffer` until it contains a complete HTTP head,
+/// returning the length of the head
+fn poll_read_head<IO: ExtendedRead>(io: &mut IO, task_handle: &mut TaskHandle, buffer: &mut Vec<u8>)
+  -> ExtendedPoll<usize, io::Error>
+{
+  loop {
+    if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+      return Ok(ExtendedAsync::Ready(end + 4));
+    }
+
+    if buffer.len() > MAX_HEAD_LENGTH {
+      return Err(protocol_error("handshake too long"));
+    }
+
+    let mut chunk = [0; 1024];
+    match extended_try_ready!(io.extended_read(task_handle, &mut chunk)) {
+      0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during handshake")),
+      n => buffer.extend_from_slice(&chunk[..n]),
+    }
+  }
+}
+
+/// Write all of `buffer` to `io`, keeping track of progress in `written`
+fn poll_write_all<IO: ExtendedWrite>(io: &mut IO, task_handle: &mut TaskHandle, buffer: &[u8], written: &mut usize)
+  -> ExtendedPoll<(), io::Error>
+{
+  while *written < buffer.len() {
+    match extended_try_ready!(io.extended_write(task_handle, &buffer[*written..])) {
+      0 => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write handshake")),
+      n => *written += n,
+    }
+  }
+  io.extended_flush(task_handle)
+}
+
+/// Perform the client side of the websocket handshake over `io`, requesting
+/// `path` from `host`
+pub fn client<IO>(io: IO, host: &str, path: &str) -> ClientHandshake<IO> {
+  let key = base64(&random::<[u8; 16]>());
+  let request = format!(
+    "GET {} HTTP/1.1\r\n\
+     Host: {}\r\n\
+     Upgrade: websocket\r\n\
+     Connection: Upgrade\r\n\
+     Sec-WebSocket-Key: {}\r\n\
+     Sec-WebSocket-Version: 13\r\n\
+     \r\n",
+    path, host, key
+  );
+
+  ClientHandshake {
+    io:       Some(io),
+    request:  request.into_bytes(),
+    written:  0,
+    response: Vec::new(),
+    accept:   accept_key(&key),
+  }
+}
+
+/// A future which performs the client side of the websocket handshake, and
+/// resolves to a `WebSocket`
+pub struct ClientHandshake<IO> {
+  io:       Option<IO>,
+  request:  Vec<u8>,
+  written:  usize,
+  response: Vec<u8>,
+  accept:   String,
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> ExtendedFuture for ClientHandshake<IO> {
+  type Item = WebSocket<IO>;
+  type Error = io::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    let end = {
+      let io = self.io.as_mut().expect("handshake polled after completion");
+      extended_try_ready!(poll_write_all(io, task_handle, &self.request, &mut self.written));
+      extended_try_ready!(poll_read_head(io, task_handle, &mut self.response))
+    };
+
+    let head = Head::parse(&self.response[..end])?;
+
+    if !head.start_line.starts_with("HTTP/1.1 101") {
+      return Err(protocol_error(&format!("server refused upgrade: {}", head.start_line)));
+    }
+
+    if !head.has_token("upgrade", "websocket") || !head.has_token("connection", "upgrade") {
+      return Err(protocol_error("server response missing upgrade headers"));
+    }
+
+    if head.header("sec-websocket-accept") != Some(self.accept.as_str()) {
+      return Err(protocol_error("server sent incorrect accept key"));
+    }
+
+    let leftover = self.response.split_off(end);
+    let io = self.io.take().unwrap();
+    Ok(ExtendedAsync::Ready(WebSocket::from_parts(io, Role::Client, leftover)))
+  }
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> Future for ClientHandshake<IO> {
+  type Item = WebSocket<IO>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+/// Perform the server side of the websocket handshake over `io`
+pub fn accept<IO>(io: IO) -> ServerHandshake<IO> {
+  ServerHandshake {
+    io:       Some(io),
+    request:  Vec::new(),
+    leftover: Vec::new(),
+    response: Vec::new(),
+    written:  0,
+  }
+}
+
+/// A future which performs the server side of the websocket handshake, and
+/// resolves to a `WebSocket`
+pub struct ServerHandshake<IO> {
+  io:       Option<IO>,
+  request:  Vec<u8>,
+  leftover: Vec<u8>,
+  response: Vec<u8>,
+  written:  usize,
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> ServerHandshake<IO> {
+  fn respond(&mut self, end: usize) -> Result<(), io::Error> {
+    let head = Head::parse(&self.request[..end])?;
+
+    if !head.start_line.starts_with("GET ") || !head.start_line.ends_with(" HTTP/1.1") {
+      return Err(protocol_error(&format!("unexpected request: {}", head.start_line)));
+    }
+
+    if !head.has_token("upgrade", "websocket") || !head.has_token("connection", "upgrade") {
+      return Err(protocol_error("request missing upgrade headers"));
+    }
+
+    if head.header("sec-websocket-version") != Some("13") {
+      return Err(protocol_error("unsupported websocket version"));
+    }
+
+    let key = head.header("sec-websocket-key").ok_or_else(|| protocol_error("request missing key"))?;
+
+    self.response = format!(
+      "HTTP/1.1 101 Switching Protocols\r\n\
+       Upgrade: websocket\r\n\
+       Connection: Upgrade\r\n\
+       Sec-WebSocket-Accept: {}\r\n\
+       \r\n",
+      accept_key(key)
+    ).into_bytes();
+    self.leftover = self.request.split_off(end);
+    Ok(())
+  }
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> ExtendedFuture for ServerHandshake<IO> {
+  type Item = WebSocket<IO>;
+  type Error = io::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<Self::Item, Self::Error> {
+    if self.response.is_empty() {
+      let end = {
+        let io = self.io.as_mut().expect("handshake polled after completion");
+        extended_try_ready!(poll_read_head(io, task_handle, &mut self.request))
+      };
+      self.respond(end)?;
+    }
+
+    {
+      let io = self.io.as_mut().expect("handshake polled after completion");
+      extended_try_ready!(poll_write_all(io, task_handle, &self.response, &mut self.written));
+    }
+
+    let leftover = self.leftover.split_off(0);
+    let io = self.io.take().unwrap();
+    Ok(ExtendedAsync::Ready(WebSocket::from_parts(io, Role::Server, leftover)))
+  }
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> Future for ServerHandshake<IO> {
+  type Item = WebSocket<IO>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    future_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::pipe::duplex;
+
+  #[test]
+  fn sha1_test_vectors() {
+    fn hex(digest: [u8; 20]) -> String {
+      digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(
+      hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+      "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+  }
+
+  #[test]
+  fn base64_padding() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+  }
+
+  #[test]
+  fn rfc_accept_key() {
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+  }
+
+  #[test]
+  fn handshake_over_pipe() {
+    let (a, b) = duplex(64);
+    let mut core = Core::new().unwrap();
+    core.run(client(a, "localhost", "/feed").join(accept(b))).unwrap();
+  }
+
+  #[test]
+  fn server_rejects_plain_http() {
+    let (mut a, b) = duplex(1024);
+    let mut core = Core::new().unwrap();
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    assert_eq!(write_adapter(&mut a, request).unwrap(), Async::Ready(request.len()));
+    assert!(core.run(accept(b)).is_err());
+  }
+}
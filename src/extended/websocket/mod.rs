@@ -0,0 +1,359 @@
+use common::*;
+use extended::common::*;
+use extended::codec::Framed;
+
+use self::frame::{Frame, FrameCodec, Opcode, protocol_error};
+
+pub mod frame;
+pub mod handshake;
+
+pub use self::handshake::{accept, client, ClientHandshake, ServerHandshake};
+
+/// The default largest payload sent in a single data frame before a message
+/// is split into fragments
+const DEFAULT_FRAGMENT_SIZE: usize = 64 * 1024;
+
+/// Which end of the connection we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+  Client,
+  Server,
+}
+
+/// A complete websocket message
+///
+/// Fragmented messages are reassembled before they are yielded, and pings
+/// are answered automatically, although they are still yielded so that the
+/// user can observe them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+  Text(String),
+  Binary(Vec<u8>),
+  Ping(Vec<u8>),
+  Pong(Vec<u8>),
+  Close(Option<(u16, String)>),
+}
+
+/// A websocket connection over `IO`, which has already completed the HTTP
+/// upgrade handshake
+///
+/// Received messages are yielded as a stream, and messages to send are
+/// accepted as a sink. When a close message is received it is yielded, a
+/// reply is sent if we have not already sent a close message ourselves, and
+/// then the stream ends.
+pub struct WebSocket<IO> {
+  framed:         Framed<IO, FrameCodec>,
+  fragment_size:  usize,
+  fragments:      Option<(Opcode, Vec<u8>)>,
+  pending:        VecDeque<Frame>,
+  sent_close:     bool,
+  received_close: bool,
+}
+
+impl<IO> WebSocket<IO> {
+  pub fn new(io: IO, role: Role) -> WebSocket<IO> {
+    WebSocket::from_parts(io, role, Vec::new())
+  }
+
+  /// Create a websocket from `io` and any bytes which have already been read
+  /// from it after the end of the handshake
+  pub fn from_parts(io: IO, role: Role, read_buffer: Vec<u8>) -> WebSocket<IO> {
+    WebSocket {
+      framed:         Framed::from_parts(io, FrameCodec::new(role), read_buffer),
+      fragment_size:  DEFAULT_FRAGMENT_SIZE,
+      fragments:      None,
+      pending:        VecDeque::new(),
+      sent_close:     false,
+      received_close: false,
+    }
+  }
+
+  /// Split outgoing text and binary messages into frames with payloads no
+  /// longer than `fragment_size`
+  pub fn with_fragment_size(mut self, fragment_size: usize) -> WebSocket<IO> {
+    assert!(fragment_size > 0, "fragment size must be greater than zero");
+    self.fragment_size = fragment_size;
+    self
+  }
+
+  pub fn get_ref(&self) -> &IO {
+    self.framed.get_ref()
+  }
+
+  pub fn get_mut(&mut self) -> &mut IO {
+    self.framed.get_mut()
+  }
+
+  fn queue_message(&mut self, message: Message) {
+    let (opcode, payload) = match message {
+      Message::Text(text) => (Opcode::Text, text.into_bytes()),
+      Message::Binary(data) => (Opcode::Binary, data),
+      Message::Ping(data) => return self.pending.push_back(Frame::new(Opcode::Ping, data)),
+      Message::Pong(data) => return self.pending.push_back(Frame::new(Opcode::Pong, data)),
+      Message::Close(reason) => {
+        let mut payload = Vec::new();
+        if let Some((code, reason)) = reason {
+          payload.extend_from_slice(&[(code >> 8) as u8, code as u8]);
+          payload.extend_from_slice(reason.as_bytes());
+        }
+        self.sent_close = true;
+        return self.pending.push_back(Frame::new(Opcode::Close, payload));
+      }
+    };
+
+    if payload.len() <= self.fragment_size {
+      return self.pending.push_back(Frame::new(opcode, payload));
+    }
+
+    let count = payload.len().div_ceil(self.fragment_size);
+    for (i, chunk) in payload.chunks(self.fragment_size).enumerate() {
+      self.pending.push_back(Frame {
+        fin:     i == count - 1,
+        opcode:  if i == 0 { opcode } else { Opcode::Continuation },
+        payload: chunk.to_vec(),
+      });
+    }
+  }
+
+  /// Handle a received data frame, returning a message if it completes one
+  fn receive_data(&mut self, frame: Frame) -> Result<Option<Message>, io::Error> {
+    let (opcode, payload) = match (frame.opcode, self.fragments.take()) {
+      (Opcode::Continuation, Some((opcode, mut payload))) => {
+        payload.extend_from_slice(&frame.payload);
+        (opcode, payload)
+      }
+      (Opcode::Continuation, None) => return Err(protocol_error("unexpected continuation frame")),
+      (_, Some(_)) => return Err(protocol_error("new message started before previous message finished")),
+      (opcode, None) => (opcode, frame.payload),
+    };
+
+    if !frame.fin {
+      self.fragments = Some((opcode, payload));
+      return Ok(None);
+    }
+
+    match opcode {
+      Opcode::Text => String::from_utf8(payload)
+        .map(|text| Some(Message::Text(text)))
+        .map_err(|_| protocol_error("text message was not valid UTF-8")),
+      _ => Ok(Some(Message::Binary(payload))),
+    }
+  }
+
+  fn receive_close(&mut self, payload: Vec<u8>) -> Result<Message, io::Error> {
+    self.received_close = true;
+
+    let reason = match payload.len() {
+      0 => None,
+      1 => return Err(protocol_error("close frame with truncated status code")),
+      _ => {
+        let code = (payload[0] as u16) << 8 | payload[1] as u16;
+        let reason = String::from_utf8(payload[2..].to_vec())
+          .map_err(|_| protocol_error("close reason was not valid UTF-8"))?;
+        Some((code, reason))
+      }
+    };
+
+    if !self.sent_close {
+      // echo the status code back, as recommended by the RFC
+      self.sent_close = true;
+      let reply = payload[..payload.len().min(2)].to_vec();
+      self.pending.push_back(Frame::new(Opcode::Close, reply));
+    }
+
+    Ok(Message::Close(reason))
+  }
+}
+
+impl<IO: ExtendedWrite> WebSocket<IO> {
+  /// Write any queued frames, and then flush the underlying io
+  fn poll_pending(&mut self, task_handle: &mut TaskHandle) -> ExtendedPoll<(), io::Error> {
+    while let Some(frame) = self.pending.pop_front() {
+      if let ExtendedAsyncSink::NotReady(frame, agreement_to_notify) = self.framed.extended_start_send(task_handle, frame)? {
+        self.pending.push_front(frame);
+        return Ok(ExtendedAsync::NotReady(agreement_to_notify));
+      }
+    }
+    self.framed.extended_poll_complete(task_handle)
+  }
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> ExtendedStream for WebSocket<IO> {
+  type Item = Message;
+  type Error = io::Error;
+
+  fn extended_poll(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<Option<Self::Item>>, Self::Error>
+  {
+    if self.received_close {
+      extended_try_ready!(self.poll_pending(task_handle));
+      return Ok(ExtendedAsync::Ready(None));
+    }
+
+    loop {
+      // make progress on any automatic replies, but don't wait for them to be
+      // written before reading, since the underlying io will notify us when
+      // it is writable again
+      self.poll_pending(task_handle)?;
+
+      let frame = match extended_try_ready!(self.framed.extended_poll(task_handle)) {
+        Some(frame) => frame,
+        None => return Ok(ExtendedAsync::Ready(None)),
+      };
+
+      let message = match frame.opcode {
+        Opcode::Ping => {
+          self.pending.push_back(Frame::new(Opcode::Pong, frame.payload.clone()));
+          Message::Ping(frame.payload)
+        }
+        Opcode::Pong => Message::Pong(frame.payload),
+        Opcode::Close => self.receive_close(frame.payload)?,
+        Opcode::Text | Opcode::Binary | Opcode::Continuation => match self.receive_data(frame)? {
+          Some(message) => message,
+          None => continue,
+        },
+      };
+
+      // start writing any reply straight away, so that the peer is answered
+      // even if we aren't polled again for a while
+      self.poll_pending(task_handle)?;
+      return Ok(ExtendedAsync::Ready(Some(message)));
+    }
+  }
+}
+
+impl<IO: ExtendedRead + ExtendedWrite> Stream for WebSocket<IO> {
+  type Item = Message;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    stream_adapter(self)
+  }
+}
+
+impl<IO: ExtendedWrite> ExtendedSink for WebSocket<IO> {
+  type SinkItem = Message;
+  type SinkError = io::Error;
+
+  fn extended_start_send(&mut self, task_handle: &mut TaskHandle, item: Self::SinkItem)
+    -> Result<ExtendedAsyncSink<Self::SinkItem>, Self::SinkError>
+  {
+    if self.sent_close {
+      return Err(protocol_error("message sent after close"));
+    }
+
+    if let ExtendedAsync::NotReady(agreement_to_notify) = self.poll_pending(task_handle)? {
+      if !self.pending.is_empty() {
+        return Ok(ExtendedAsyncSink::NotReady(item, agreement_to_notify));
+      }
+    }
+
+    self.queue_message(item);
+    self.poll_pending(task_handle)?;
+    Ok(ExtendedAsyncSink::Ready)
+  }
+
+  fn extended_poll_complete(&mut self, task_handle: &mut TaskHandle)
+    -> Result<ExtendedAsync<()>, Self::SinkError>
+  {
+    self.poll_pending(task_handle)
+  }
+}
+
+impl<IO: ExtendedWrite> Sink for WebSocket<IO> {
+  type SinkItem = Message;
+  type SinkError = io::Error;
+
+  fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    sink_start_send_adapter(self, item)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    sink_poll_complete_adapter(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use extended::pipe::duplex;
+  use tokio_core::net::{TcpListener, TcpStream};
+
+  fn exchange<IO: ExtendedRead + ExtendedWrite>(core: &mut Core, socket: WebSocket<IO>, message: Message)
+    -> (Option<Message>, WebSocket<IO>)
+  {
+    core.run(socket.send(message).and_then(|socket| socket.into_future().map_err(|(err, _)| err))).unwrap()
+  }
+
+  #[test]
+  fn echo_over_tcp() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    handle.spawn(
+      listener.incoming().into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(connection, _)| accept(connection.unwrap().0))
+        .and_then(|socket| {
+          let (sink, stream) = socket.split();
+          stream
+            .filter(|message| matches!(*message, Message::Text(_) | Message::Binary(_)))
+            .forward(sink)
+        })
+        .map(|_| ())
+        .map_err(|err| panic!("server failed: {}", err))
+    );
+
+    let socket = core.run(
+      TcpStream::connect(&address, &handle).and_then(|stream| client(stream, "localhost", "/echo"))
+    ).unwrap().with_fragment_size(16);
+
+    let (reply, socket) = exchange(&mut core, socket, Message::Text("hello".into()));
+    assert_eq!(reply, Some(Message::Text("hello".into())));
+
+    let (reply, socket) = exchange(&mut core, socket, Message::Binary(vec![0, 1, 2, 255]));
+    assert_eq!(reply, Some(Message::Binary(vec![0, 1, 2, 255])));
+
+    let long = "a fragmented message ".repeat(20);
+    let (reply, socket) = exchange(&mut core, socket, Message::Text(long.clone()));
+    assert_eq!(reply, Some(Message::Text(long)));
+
+    let (reply, socket) = exchange(&mut core, socket, Message::Ping(b"are you there?".to_vec()));
+    assert_eq!(reply, Some(Message::Pong(b"are you there?".to_vec())));
+
+    let (reply, socket) = exchange(&mut core, socket, Message::Close(Some((1000, "bye".into()))));
+    assert_eq!(reply, Some(Message::Close(Some((1000, String::new())))));
+
+    let (end, _) = core.run(socket.into_future().map_err(|(err, _)| err)).unwrap();
+    assert_eq!(end, None);
+  }
+
+  #[test]
+  fn pings_are_answered_without_sending() {
+    let (a, b) = duplex(1024);
+    let mut core = Core::new().unwrap();
+    let client_socket = WebSocket::new(a, Role::Client);
+    let server_socket = WebSocket::new(b, Role::Server);
+
+    let (reply, _server_socket) = core.run(
+      client_socket.send(Message::Ping(vec![1, 2, 3]))
+        .join(server_socket.into_future().map_err(|(err, _)| err))
+        .and_then(|(client_socket, (ping, server_socket))| {
+          assert_eq!(ping, Some(Message::Ping(vec![1, 2, 3])));
+          client_socket.into_future().map_err(|(err, _)| err).map(move |(reply, _)| (reply, server_socket))
+        })
+    ).unwrap();
+
+    assert_eq!(reply, Some(Message::Pong(vec![1, 2, 3])));
+  }
+
+  #[test]
+  fn sending_after_close_is_an_error() {
+    let (a, _b) = duplex(1024);
+    let mut core = Core::new().unwrap();
+    let socket = core.run(WebSocket::new(a, Role::Client).send(Message::Close(None))).unwrap();
+    assert!(core.run(socket.send(Message::Text("too late".into()))).is_err());
+  }
+}
@@ -1,22 +1,35 @@
+#![allow(clippy::new_without_default)]
+
 #[macro_use]
 extern crate futures;
 extern crate rand;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
 extern crate tokio_core;
 extern crate void;
 
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
+
 /// Common types used all over the place
 pub mod common {
   pub use futures::prelude::*;
   pub use futures::task;
   pub use futures::task::Task;
   pub use rand::random;
+  pub use rng::Random;
   pub use std::cell::RefCell;
-  pub use std::collections::{HashSet, VecDeque};
-  pub use std::sync::Arc;
+  pub use std::collections::{HashMap, HashSet, VecDeque};
+  pub use std::marker::PhantomData;
+  pub use std::sync::{Arc, Mutex};
   pub use std::sync::atomic::{AtomicBool, Ordering};
   pub use std::thread;
   pub use std::time::{Duration, Instant};
-  pub use std::{fmt, io};
+  pub use std::{fmt, io, iter, ops};
   pub use tokio_core::reactor::Core;
   pub use void::Void;
 }
@@ -44,3 +57,22 @@ pub mod standard;
 /// Simple Futures, Sinks, and Streams using the extended futures API
 pub mod extended;
 
+
+/// Random values which can be made reproducible by seeding
+pub mod rng;
+
+/// Recordings of the items that arrive at a sink, for use in tests
+pub mod recording;
+
+/// Limits and shutdown signals which end series producers
+pub mod limit;
+
+/// Bounded buffers with policies for items that arrive when they are full
+pub mod overflow;
+
+/// Scripted scenarios which check that standard and extended implementations
+/// behave identically
+pub mod conformance;
+
+/// A wrapper which checks that a Future, Stream, or Sink keeps its contract
+pub mod contract;
@@ -0,0 +1,171 @@
+use common::*;
+
+struct State {
+  fired:   bool,
+  next_id: usize,
+  /// tasks waiting for the signal, by listener
+  waiting: HashMap<usize, Task>,
+}
+
+/// A signal which tells producers to end their streams
+///
+/// Cloning a `Shutdown` produces another handle to the same signal.
+#[derive(Clone)]
+pub struct Shutdown {
+  state: Arc<Mutex<State>>,
+}
+
+impl Shutdown {
+  pub fn new() -> Shutdown {
+    Shutdown{state: Arc::new(Mutex::new(State{fired: false, next_id: 0, waiting: HashMap::new()}))}
+  }
+
+  /// Fire the signal, waking everything that is listening for it
+  pub fn fire(&self) {
+    let waiting = {
+      let mut state = self.state.lock().unwrap();
+      state.fired = true;
+      state.waiting.drain().map(|(_, task)| task).collect::<Vec<Task>>()
+    };
+    for task in waiting {
+      task.notify();
+    }
+  }
+
+  pub fn is_fired(&self) -> bool {
+    self.state.lock().unwrap().fired
+  }
+
+  fn listen(&self) -> Listener {
+    let mut state = self.state.lock().unwrap();
+    let id = state.next_id;
+    state.next_id += 1;
+    Listener{id, state: self.state.clone()}
+  }
+}
+
+/// A single producer's registration with a `Shutdown`
+struct Listener {
+  id:    usize,
+  state: Arc<Mutex<State>>,
+}
+
+impl Listener {
+  fn is_fired(&self) -> bool {
+    self.state.lock().unwrap().fired
+  }
+
+  /// Wake `task` when the signal fires, replacing any task registered
+  /// previously
+  fn wake(&self, task: Task) {
+    let mut state = self.state.lock().unwrap();
+    if state.fired {
+      task.notify();
+    } else {
+      state.waiting.insert(self.id, task);
+    }
+  }
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    self.state.lock().unwrap().waiting.remove(&self.id);
+  }
+}
+
+/// When a series producer should end its stream: after a number of items,
+/// at a deadline, or when a shutdown signal fires, whichever comes first
+pub struct Limit {
+  remaining: Option<usize>,
+  deadline:  Option<Instant>,
+  shutdown:  Option<Listener>,
+}
+
+impl Limit {
+  /// Never end
+  pub fn none() -> Limit {
+    Limit{remaining: None, deadline: None, shutdown: None}
+  }
+
+  pub fn items(&mut self, n: usize) {
+    self.remaining = Some(n);
+  }
+
+  pub fn deadline(&mut self, deadline: Instant) {
+    self.deadline = Some(deadline);
+  }
+
+  pub fn shutdown(&mut self, shutdown: &Shutdown) {
+    self.shutdown = Some(shutdown.listen());
+  }
+
+  /// Whether the stream should end now
+  pub fn reached(&self) -> bool {
+    self.remaining == Some(0)
+      || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+      || self.shutdown.as_ref().is_some_and(Listener::is_fired)
+  }
+
+  /// Count an item as produced
+  pub fn produced(&mut self) {
+    if let Some(ref mut remaining) = self.remaining {
+      *remaining -= 1;
+    }
+  }
+
+  /// How long to wait for the next item, which is `interval`, unless the
+  /// deadline comes first
+  pub fn wait(&self, interval: Duration) -> Duration {
+    match self.deadline {
+      Some(deadline) => interval.min(deadline.saturating_duration_since(Instant::now())),
+      None => interval,
+    }
+  }
+
+  /// Wake `task` if the shutdown signal fires while it is waiting
+  pub fn wake_on_shutdown(&self, task: Task) {
+    if let Some(ref shutdown) = self.shutdown {
+      shutdown.wake(task);
+    }
+  }
+}
+
+impl fmt::Debug for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    f.debug_struct("Limit")
+      .field("remaining", &self.remaining)
+      .field("deadline", &self.deadline)
+      .field("shutdown", &self.shutdown.is_some())
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn limits() {
+    let mut limit = Limit::none();
+    assert!(!limit.reached());
+    limit.items(1);
+    assert!(!limit.reached());
+    limit.produced();
+    assert!(limit.reached());
+
+    let mut limit = Limit::none();
+    limit.deadline(Instant::now() + Duration::from_millis(50));
+    assert!(limit.wait(Duration::from_secs(1)) <= Duration::from_millis(50));
+    assert!(!limit.reached());
+    thread::sleep(Duration::from_millis(50));
+    assert!(limit.reached());
+
+    let shutdown = Shutdown::new();
+    let mut limit = Limit::none();
+    limit.shutdown(&shutdown);
+    assert!(!limit.reached());
+    shutdown.clone().fire();
+    assert!(limit.reached());
+    assert!(shutdown.is_fired());
+  }
+}
@@ -0,0 +1,174 @@
+use common::*;
+
+/// What a buffer does with an item that arrives when it is full
+#[derive(Debug)]
+pub enum Overflow<T = u8> {
+  /// Refuse the item, applying backpressure until there is room
+  Block,
+  /// Drop the item that has been waiting longest to make room
+  DropOldest,
+  /// Drop the item that just arrived
+  DropNewest,
+  /// Replace the buffered item with the same key, keeping its place in the
+  /// queue, so that at most one item per key is buffered, and refuse items
+  /// with new keys when full
+  ConflateByKey(fn(&T) -> u64),
+}
+
+impl<T> Clone for Overflow<T> {
+  fn clone(&self) -> Overflow<T> {
+    *self
+  }
+}
+
+impl<T> Copy for Overflow<T> {}
+
+/// A bounded queue which applies an `Overflow` policy when it is full, and
+/// counts the items it drops
+#[derive(Debug)]
+pub struct Buffer<T = u8> {
+  items:    VecDeque<T>,
+  capacity: usize,
+  overflow: Overflow<T>,
+  dropped:  u64,
+}
+
+impl<T> Buffer<T> {
+  pub fn new(capacity: usize, overflow: Overflow<T>) -> Buffer<T> {
+    assert!(capacity > 0, "buffer capacity must be greater than zero");
+    Buffer{items: VecDeque::with_capacity(capacity), capacity, overflow, dropped: 0}
+  }
+
+  /// Add `item` to the back of the queue, applying the overflow policy if
+  /// it is full, and returning how many items were dropped, or `item` if it
+  /// was refused
+  pub fn offer(&mut self, item: T) -> Result<u64, T> {
+    // conflation applies whether or not the buffer is full, so that a stale
+    // item is never delivered after a newer one with the same key
+    if let Overflow::ConflateByKey(key) = self.overflow {
+      let k = key(&item);
+      if let Some(buffered) = self.items.iter_mut().find(|buffered| key(buffered) == k) {
+        *buffered = item;
+        self.dropped += 1;
+        return Ok(1);
+      }
+    }
+
+    if self.items.len() < self.capacity {
+      self.items.push_back(item);
+      return Ok(0);
+    }
+
+    match self.overflow {
+      Overflow::Block | Overflow::ConflateByKey(_) => return Err(item),
+      Overflow::DropOldest => {
+        self.items.pop_front();
+        self.items.push_back(item);
+      }
+      Overflow::DropNewest => {}
+    }
+
+    self.dropped += 1;
+    Ok(1)
+  }
+
+  pub fn pop_front(&mut self) -> Option<T> {
+    self.items.pop_front()
+  }
+
+  /// Put back an item taken with `pop_front`, which was not sent
+  pub fn push_front(&mut self, item: T) {
+    self.items.push_front(item);
+  }
+
+  pub fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.items.is_empty()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// The number of items dropped or replaced because the buffer was full
+  pub fn dropped(&self) -> u64 {
+    self.dropped
+  }
+}
+
+/// Offer each of `items` to `sink` once, from inside a task, without waiting
+/// for it to make room, and return the items it refused
+#[cfg(test)]
+pub fn offer_all<S, I>(sink: &mut S, items: I) -> Vec<S::SinkItem>
+  where S: Sink, S::SinkError: fmt::Debug, I: IntoIterator<Item=S::SinkItem>
+{
+  ::futures::future::lazy(|| {
+    let mut refused = Vec::new();
+    for item in items {
+      if let AsyncSink::NotReady(item) = sink.start_send(item).unwrap() {
+        refused.push(item);
+      }
+    }
+    Ok::<_, ()>(refused)
+  }).wait().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filled(overflow: Overflow) -> Buffer {
+    let mut buffer = Buffer::new(3, overflow);
+    for item in 0..3 {
+      assert_eq!(buffer.offer(item), Ok(0));
+    }
+    buffer
+  }
+
+  fn drain(mut buffer: Buffer) -> Vec<u8> {
+    iter::from_fn(|| buffer.pop_front()).collect()
+  }
+
+  #[test]
+  fn block() {
+    let mut buffer = filled(Overflow::Block);
+    assert_eq!(buffer.offer(3), Err(3));
+    assert_eq!(buffer.dropped(), 0);
+    assert_eq!(drain(buffer), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn drop_oldest() {
+    let mut buffer = filled(Overflow::DropOldest);
+    assert_eq!(buffer.offer(3), Ok(1));
+    assert_eq!(buffer.offer(4), Ok(1));
+    assert_eq!(buffer.dropped(), 2);
+    assert_eq!(drain(buffer), vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn drop_newest() {
+    let mut buffer = filled(Overflow::DropNewest);
+    assert_eq!(buffer.offer(3), Ok(1));
+    assert_eq!(buffer.dropped(), 1);
+    assert_eq!(drain(buffer), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn conflate_by_key() {
+    // items with the same tens digit share a key
+    let mut buffer = Buffer::new(3, Overflow::ConflateByKey(|item: &u8| u64::from(*item / 10)));
+    assert_eq!(buffer.offer(10), Ok(0));
+    assert_eq!(buffer.offer(20), Ok(0));
+    // conflated before the buffer is full
+    assert_eq!(buffer.offer(11), Ok(1));
+    assert_eq!(buffer.offer(30), Ok(0));
+    assert_eq!(buffer.offer(12), Ok(1));
+    assert_eq!(buffer.offer(40), Err(40));
+    assert_eq!(buffer.dropped(), 2);
+    assert_eq!(drain(buffer), vec![12, 20, 30]);
+  }
+}
@@ -0,0 +1,170 @@
+use common::*;
+
+/// The items received by a recorder, along with the `Instant` each arrived
+///
+/// A recording is shared with the recorder that fills it, so it can be
+/// inspected after the recorder has been consumed by `forward`.
+pub struct Recording<T> {
+  arrivals: Arc<Mutex<Vec<(T, Instant)>>>,
+}
+
+impl<T> Recording<T> {
+  pub fn new() -> Recording<T> {
+    Recording{arrivals: Arc::new(Mutex::new(Vec::new()))}
+  }
+
+  /// Record that `item` has arrived
+  pub fn record(&self, item: T) {
+    self.arrivals.lock().unwrap().push((item, Instant::now()));
+  }
+
+  pub fn len(&self) -> usize {
+    self.arrivals.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// A callback which records each item it is passed, for consumers built
+  /// with `with_callback`
+  pub fn callback(&self) -> impl Fn(T) {
+    let recording = self.clone();
+    move |item| recording.record(item)
+  }
+
+  /// When each item arrived
+  pub fn arrivals(&self) -> Vec<Instant> {
+    self.arrivals.lock().unwrap().iter().map(|arrival| arrival.1).collect()
+  }
+
+  /// Assert that at least `gap` passed between each item and the next
+  pub fn assert_inter_arrival_at_least(&self, gap: Duration) {
+    let arrivals = self.arrivals();
+    for (i, pair) in arrivals.windows(2).enumerate() {
+      let elapsed = pair[1] - pair[0];
+      assert!(
+        elapsed >= gap,
+        "item {} arrived {:?} after item {}, expected at least {:?}", i + 1, elapsed, i, gap
+      );
+    }
+  }
+
+  /// Assert that at most `gap` passed between each item and the next
+  pub fn assert_inter_arrival_at_most(&self, gap: Duration) {
+    let arrivals = self.arrivals();
+    for (i, pair) in arrivals.windows(2).enumerate() {
+      let elapsed = pair[1] - pair[0];
+      assert!(
+        elapsed <= gap,
+        "item {} arrived {:?} after item {}, expected at most {:?}", i + 1, elapsed, i, gap
+      );
+    }
+  }
+}
+
+impl<T: Clone> Recording<T> {
+  /// The items received so far, in the order they arrived
+  pub fn items(&self) -> Vec<T> {
+    self.arrivals.lock().unwrap().iter().map(|arrival| arrival.0.clone()).collect()
+  }
+}
+
+impl<T: PartialEq + fmt::Debug> Recording<T> {
+  /// Assert that exactly `expected` was received, in that order
+  pub fn assert_received_in_order(&self, expected: &[T]) {
+    let arrivals = self.arrivals.lock().unwrap();
+    for (i, ((item, _), expected)) in arrivals.iter().zip(expected).enumerate() {
+      assert_eq!(item, expected, "item {} differs", i);
+    }
+    assert_eq!(arrivals.len(), expected.len(), "received a different number of items than expected");
+  }
+}
+
+impl<T> Clone for Recording<T> {
+  fn clone(&self) -> Recording<T> {
+    Recording{arrivals: self.arrivals.clone()}
+  }
+}
+
+/// When a recorder should refuse an item with `NotReady`, as a pattern of
+/// send attempts which repeats forever, where `true` means refuse
+#[derive(Debug, Clone)]
+pub struct Schedule {
+  pattern:  Vec<bool>,
+  attempts: usize,
+}
+
+impl Schedule {
+  /// Accept every item
+  pub fn never() -> Schedule {
+    Schedule{pattern: Vec::new(), attempts: 0}
+  }
+
+  pub fn new(pattern: Vec<bool>) -> Schedule {
+    Schedule{pattern, attempts: 0}
+  }
+
+  /// Whether the next send attempt should be refused
+  pub fn refuse(&mut self) -> bool {
+    if self.pattern.is_empty() {
+      return false;
+    }
+    let refuse = self.pattern[self.attempts % self.pattern.len()];
+    self.attempts += 1;
+    refuse
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn assertions() {
+    let recording = Recording::new();
+    let callback = recording.callback();
+    callback(1);
+    thread::sleep(Duration::from_millis(20));
+    callback(2);
+    recording.assert_received_in_order(&[1, 2]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(20));
+    recording.assert_inter_arrival_at_most(Duration::from_secs(1));
+    assert_eq!(recording.items(), vec![1, 2]);
+  }
+
+  #[test]
+  #[should_panic(expected = "item 1 differs")]
+  fn out_of_order() {
+    let recording = Recording::new();
+    recording.record(1);
+    recording.record(3);
+    recording.assert_received_in_order(&[1, 2]);
+  }
+
+  #[test]
+  #[should_panic(expected = "expected at least")]
+  fn too_close() {
+    let recording = Recording::new();
+    recording.record(1);
+    recording.record(2);
+    recording.assert_inter_arrival_at_least(Duration::from_secs(1));
+  }
+
+  #[test]
+  #[should_panic(expected = "expected at most")]
+  fn too_far_apart() {
+    let recording = Recording::new();
+    recording.record(1);
+    thread::sleep(Duration::from_millis(20));
+    recording.record(2);
+    recording.assert_inter_arrival_at_most(Duration::from_millis(10));
+  }
+
+  #[test]
+  fn schedule_repeats() {
+    let mut schedule = Schedule::new(vec![false, true]);
+    assert_eq!((0..4).map(|_| schedule.refuse()).collect::<Vec<bool>>(), vec![false, true, false, true]);
+    assert!(!Schedule::never().refuse());
+  }
+}
@@ -0,0 +1,55 @@
+use common::*;
+use rand::{Rand, Rng, SeedableRng, XorShiftRng};
+
+/// An endless iterator of random `u8`s, and a source of other random values,
+/// drawn from either the thread-local generator or a seeded one
+///
+/// Two `Random`s made with the same seed produce the same values, so a
+/// pipeline built from seeded producers behaves identically on every run.
+#[derive(Debug, Clone)]
+pub struct Random {
+  seeded: Option<XorShiftRng>,
+}
+
+impl Random {
+  /// Draw values from the thread-local generator
+  pub fn new() -> Random {
+    Random{seeded: None}
+  }
+
+  /// Draw values from a generator seeded with `seed`
+  pub fn with_seed(seed: u64) -> Random {
+    let (low, high) = (seed as u32, (seed >> 32) as u32);
+    // xorshift can't be seeded with all zeros, so mix in some constants
+    let rng = XorShiftRng::from_seed([low, high, low ^ 0x9e37_79b9, high ^ 0x7f4a_7c15]);
+    Random{seeded: Some(rng)}
+  }
+
+  pub fn gen<T: Rand>(&mut self) -> T {
+    match self.seeded {
+      Some(ref mut rng) => rng.gen(),
+      None => random(),
+    }
+  }
+}
+
+impl Iterator for Random {
+  type Item = u8;
+
+  fn next(&mut self) -> Option<u8> {
+    Some(self.gen())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn seeded_values_repeat() {
+    let a = Random::with_seed(7).take(100).collect::<Vec<u8>>();
+    assert_eq!(a, Random::with_seed(7).take(100).collect::<Vec<u8>>());
+    assert_ne!(a, Random::with_seed(8).take(100).collect::<Vec<u8>>());
+    assert_eq!(Random::with_seed(0).gen::<f64>(), Random::with_seed(0).gen::<f64>());
+  }
+}
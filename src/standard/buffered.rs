@@ -1,73 +1,97 @@
 use common::*;
+use overflow::{Buffer, Overflow};
 use standard;
 
-const BUFFER_SIZE: usize = 10;
-
-/// A sink that consumes one item every second, but which can buffer up to
-/// BUFFER_SIZE items
-pub struct Consumer {
-  buffer: VecDeque<u8>,
-  inner:  standard::delayed_series::Consumer,
+/// A sink that consumes one item every second, by passing it to a callback
+/// which by default discards it, but which can buffer up to `capacity`
+/// items, applying an `Overflow` policy when the buffer is full
+pub struct Consumer<F = fn(u8)> {
+  buffer: Buffer,
+  inner:  standard::delayed_series::Consumer<u8, F>,
 }
 
 impl Consumer {
-  pub fn new() -> Consumer {
+  /// A consumer which applies backpressure when its buffer is full
+  pub fn new(capacity: usize) -> Consumer {
+    Consumer::with_callback(capacity, drop)
+  }
+}
+
+impl<F: FnMut(u8)> Consumer<F> {
+  /// A consumer which passes each item to `callback`
+  pub fn with_callback(capacity: usize, callback: F) -> Consumer<F> {
     Consumer {
-      buffer: VecDeque::with_capacity(BUFFER_SIZE),
-      inner:  standard::delayed_series::Consumer::new(),
+      buffer: Buffer::new(capacity, Overflow::Block),
+      inner:  standard::delayed_series::Consumer::with_callback(callback),
     }
   }
 
-  fn try_buffer(&mut self, item: u8) -> AsyncSink<u8> {
-    if self.buffer.len() < BUFFER_SIZE {
-      self.buffer.push_back(item);
-      AsyncSink::Ready
-    } else {
-      AsyncSink::NotReady(item)
+  /// Apply `overflow` when the buffer is full
+  pub fn with_overflow(self, overflow: Overflow) -> Consumer<F> {
+    Consumer{buffer: Buffer::new(self.buffer.capacity(), overflow), ..self}
+  }
+
+  /// The number of items dropped or replaced because the buffer was full
+  pub fn dropped(&self) -> u64 {
+    self.buffer.dropped()
+  }
+
+  fn try_empty_buffer(&mut self) -> Poll<(), Void> {
+    while let Some(item) = self.buffer.pop_front() {
+      if let AsyncSink::NotReady(item) = self.inner.start_send(item)? {
+        self.buffer.push_front(item);
+
+        // ensure that we attempt to complete any pushes we've started
+        self.inner.poll_complete()?;
+
+        return Ok(Async::NotReady);
+      }
     }
+
+    Ok(Async::Ready(()))
   }
 }
 
-impl Sink for Consumer {
+impl<F: FnMut(u8)> Sink for Consumer<F> {
   type SinkItem = u8;
   type SinkError = Void;
 
   fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-    match self.inner.start_send(item)? {
-      AsyncSink::NotReady(item) => Ok(self.try_buffer(item)),
-      AsyncSink::Ready => Ok(AsyncSink::Ready),
+    if let Async::NotReady = self.try_empty_buffer()? {
+      match self.buffer.offer(item) {
+        Ok(_) => Ok(AsyncSink::Ready),
+        Err(item) => Ok(AsyncSink::NotReady(item)),
+      }
+    } else {
+      assert!(self.buffer.is_empty());
+      self.buffer.offer(item).expect("empty buffer refused an item");
+      Ok(AsyncSink::Ready)
     }
   }
 
   fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-    try_ready!(self.inner.poll_complete());
-
-    while let Some(item) = self.buffer.pop_front() {
-      if let AsyncSink::NotReady(item) = self.inner.start_send(item)? {
-        self.buffer.push_front(item);
-        break;
-      }
-      try_ready!(self.inner.poll_complete());
-    }
-    Ok(Async::Ready(()))
+    try_ready!(self.try_empty_buffer());
+    debug_assert!(self.buffer.is_empty());
+    self.inner.poll_complete()
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use recording::Recording;
 
   #[test]
   fn production_and_consumption_are_concurrent() {
     let mut core = Core::new().unwrap();
-    let start = Instant::now();
-    let producer = standard::delayed_series::Producer::new().take(5);
-    let consumer = Consumer::new();
+    let recording = Recording::new();
+    let producer = standard::delayed_series::Producer::from_items(0..5);
+    let consumer = Consumer::with_callback(10, recording.callback());
 
-    core.run(producer.forward(consumer)).unwrap();
+    let _ = core.run(producer.forward(consumer)).unwrap();
 
-    let elapsed = start.elapsed();
-    assert!(elapsed < Duration::new(6, 500_000_000));
-    assert!(elapsed > Duration::new(5, 500_000_000));
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    recording.assert_inter_arrival_at_least(Duration::from_millis(900));
+    recording.assert_inter_arrival_at_most(Duration::from_millis(1500));
   }
 }
@@ -1,41 +1,92 @@
 use common::*;
-use standard;
+use limit::{Limit, Shutdown};
 
-/// A Stream that produces a stream of random `u8`s with no delay
+/// A Stream that produces values from an iterator with no delay, by default
+/// an endless series of random `u8`s
 #[derive(Debug)]
-pub struct Producer {
-  next: standard::instant::Producer,
+pub struct Producer<I = Random> {
+  items: I,
+  limit: Limit,
 }
 
 impl Producer {
   pub fn new() -> Producer {
-    Producer{next: standard::instant::Producer::new()}
+    Producer::from_items(Random::new())
+  }
+
+  /// A producer of random `u8`s drawn from a generator seeded with `seed`,
+  /// which are the same on every run
+  pub fn with_seed(seed: u64) -> Producer {
+    Producer::from_items(Random::with_seed(seed))
+  }
+}
+
+impl<T, F: FnMut() -> T> Producer<iter::RepeatWith<F>> {
+  /// An endless producer of values made by `generate`
+  pub fn from_fn(generate: F) -> Producer<iter::RepeatWith<F>> {
+    Producer::from_items(iter::repeat_with(generate))
+  }
+}
+
+impl<I: Iterator> Producer<I> {
+  /// A producer of `items`, which ends when they run out
+  pub fn from_items<J: IntoIterator<IntoIter=I>>(items: J) -> Producer<I> {
+    Producer{items: items.into_iter(), limit: Limit::none()}
+  }
+
+  /// End the stream after `n` more items
+  pub fn end_after(mut self, n: usize) -> Producer<I> {
+    self.limit.items(n);
+    self
+  }
+
+  /// End the stream at `deadline`
+  pub fn end_at(mut self, deadline: Instant) -> Producer<I> {
+    self.limit.deadline(deadline);
+    self
+  }
+
+  /// End the stream when `shutdown` fires
+  pub fn end_on(mut self, shutdown: &Shutdown) -> Producer<I> {
+    self.limit.shutdown(shutdown);
+    self
   }
 }
 
-impl Stream for Producer {
-  type Item = u8;
+impl<I: Iterator> Stream for Producer<I> {
+  type Item = I::Item;
   type Error = Void;
   fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-    let next = try_ready!(self.next.poll());
-    self.next = standard::instant::Producer::new();
-    Ok(Async::Ready(Some(next)))
+    if self.limit.reached() {
+      return Ok(Async::Ready(None));
+    }
+    self.limit.produced();
+    Ok(Async::Ready(self.items.next()))
   }
 }
 
-/// A Sink that consumes `u8`s with no delay
-pub struct Consumer {
-  sending: Option<standard::instant::Consumer>,
+/// A Sink that consumes values with no delay, by passing them to a callback
+/// which by default discards them
+pub struct Consumer<T = u8, F = fn(T)> {
+  sending:  Option<T>,
+  callback: F,
 }
 
 impl Consumer {
   pub fn new() -> Consumer {
-    Consumer{sending: None}
+    Consumer::with_callback(drop)
+  }
+}
+
+impl<T, F: FnMut(T)> Consumer<T, F> {
+  /// A consumer which passes each value to `callback`
+  pub fn with_callback(callback: F) -> Consumer<T, F> {
+    Consumer{sending: None, callback}
   }
 }
 
-impl Sink for Consumer {
-  type SinkItem = u8;
+impl<T, F: FnMut(T)> Sink for Consumer<T, F> {
+  type SinkItem = T;
   type SinkError = Void;
 
   fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
@@ -46,15 +97,14 @@ impl Sink for Consumer {
     if self.sending.is_some() {
       Ok(AsyncSink::NotReady(item))
     } else {
-      self.sending = Some(standard::instant::Consumer::new(item));
+      self.sending = Some(item);
       Ok(AsyncSink::Ready)
     }
   }
 
   fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-    if self.sending.is_some() {
-      try_ready!(self.sending.as_mut().unwrap().poll());
-      self.sending = None;
+    if let Some(item) = self.sending.take() {
+      (self.callback)(item);
     }
     Ok(Async::Ready(()))
   }
@@ -64,6 +114,7 @@ impl Sink for Consumer {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use standard;
 
   #[test]
   fn producer_returns_all_values() {
@@ -88,8 +139,57 @@ mod tests {
         .take(expected);
       let consumer = Consumer::new();
 
-      producer.forward(consumer).wait().unwrap();
+      let _ = producer.forward(consumer).wait().unwrap();
     }
     assert_eq!(produced, expected);
   }
+
+  #[test]
+  fn forward_to_recorder() {
+    let expected = Random::with_seed(5).take(1000).collect::<Vec<u8>>();
+    let recorder = standard::recorder::Recorder::new();
+    let recording = recorder.recording();
+    let _ = Producer::with_seed(5).take(1000).forward(recorder).wait().unwrap();
+    recording.assert_received_in_order(&expected);
+  }
+
+  #[test]
+  fn generic() {
+    let mut consumed = Vec::new();
+    {
+      let producer = Producer::from_items(vec!["a", "b", "c"]);
+      let consumer = Consumer::with_callback(|item| consumed.push(item));
+      let _ = producer.forward(consumer).wait().unwrap();
+    }
+    assert_eq!(consumed, vec!["a", "b", "c"]);
+
+    let seeded = Producer::with_seed(1).take(100).collect().wait().unwrap();
+    assert_eq!(seeded, Producer::with_seed(1).take(100).collect().wait().unwrap());
+
+    let mut n = 0;
+    let producer = Producer::from_fn(|| { n += 1; n });
+    assert_eq!(producer.take(3).collect().wait().unwrap(), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn bounded() {
+    assert_eq!(Producer::new().end_after(3).collect().wait().unwrap().len(), 3);
+    assert_eq!(Producer::from_items(0..10).end_after(20).collect().wait().unwrap().len(), 10);
+    assert!(Producer::new().end_at(Instant::now()).collect().wait().unwrap().is_empty());
+
+    let shutdown = Shutdown::new();
+    let mut producer = Producer::from_items(0..10).end_on(&shutdown).wait();
+    assert_eq!(producer.next(), Some(Ok(0)));
+    shutdown.fire();
+    assert_eq!(producer.next(), None);
+  }
+
+  #[test]
+  fn forward_completes_at_stream_end() {
+    let recorder = standard::recorder::Recorder::new();
+    let recording = recorder.recording();
+    let (producer, _recorder) = Producer::from_items(0..5).forward(recorder).wait().unwrap();
+    recording.assert_received_in_order(&[0, 1, 2, 3, 4]);
+    assert!(producer.collect().wait().unwrap().is_empty());
+  }
 }
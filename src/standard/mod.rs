@@ -4,3 +4,4 @@ pub mod delayed;
 pub mod instant_series;
 pub mod delayed_series;
 pub mod buffered;
+pub mod recorder;